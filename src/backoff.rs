@@ -0,0 +1,84 @@
+use crate::cfg::Config;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// exponential backoff with jitter between the restart attempts of a tunnel,
+/// the first attempt is immediate
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: f64,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(config: &Config) -> Self {
+        Backoff {
+            initial: config.get_probe_backoff_initial(),
+            max: config.get_probe_backoff_max(),
+            multiplier: config.get_probe_backoff_multiplier(),
+            jitter: config.get_probe_backoff_jitter(),
+            attempts: 0,
+        }
+    }
+
+    /// the wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        self.attempts += 1;
+        if self.attempts == 1 {
+            return Duration::from_secs(0);
+        }
+        let base = self.initial.as_secs_f64() * self.multiplier.powi(self.attempts as i32 - 2);
+        let base = base.min(self.max.as_secs_f64());
+        // random in [-jitter, jitter]
+        let jitter = self.jitter * (get_random() * 2.0 - 1.0);
+        Duration::from_secs_f64((base * (1.0 + jitter)).max(0.0))
+    }
+
+    /// start over after the tunnel is stable
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// a random number in [0, 1) good enough for jitter
+fn get_random() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.subsec_nanos())
+        .unwrap_or(0);
+    let mut x = ((nanos as u64) ^ ((std::process::id() as u64) << 32)) | 1;
+    // xorshift to spread the bits
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backoff::Backoff;
+    use crate::cfg::Config;
+    use std::time::Duration;
+
+    #[test]
+    fn test_next_delay() {
+        let config: Config = toml::from_str(
+            r#"
+            probe_backoff_initial = 10
+            probe_backoff_max = 30
+            probe_backoff_multiplier = 2
+            probe_backoff_jitter = 0
+            "#,
+        )
+        .unwrap();
+        let mut backoff = Backoff::new(&config);
+        let delays = (0..5)
+            .map(|_| backoff.next_delay().as_secs())
+            .collect::<Vec<u64>>();
+        assert_eq!(delays, vec![0, 10, 20, 30, 30]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(0));
+    }
+}
@@ -1,35 +1,538 @@
+pub mod effective;
+mod overrides;
+mod validate;
+
+use crate::error::{self, SshpError};
 use crate::utils;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// name of the legacy `[dynamic_proxy]` section when used as a tunnel
+pub const DYNAMIC_PROXY: &str = "dynamic_proxy";
+/// name of the legacy `[multi_proxy]` section when used as a tunnel
+pub const MULTI_PROXY: &str = "multi_proxy";
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     probe_check_interval: Option<i32>,
     probe_failed_times_when_exit: Option<i32>,
+    /// seconds to wait after SIGTERM before SIGKILL when stopping
+    stop_timeout: Option<u64>,
+    /// seconds to wait for ssh to listen when starting
+    startup_timeout: Option<u64>,
+    /// seconds to wait before the second restart attempt in probe
+    probe_backoff_initial: Option<u64>,
+    /// max seconds to wait between restart attempts
+    probe_backoff_max: Option<u64>,
+    probe_backoff_multiplier: Option<f64>,
+    /// random fraction added to or subtracted from the wait, between 0 and 1
+    probe_backoff_jitter: Option<f64>,
+    /// seconds a restarted tunnel stays alive to reset the wait
+    probe_stable_period: Option<u64>,
+    /// seconds between health checks through the tunnel in probe, 0 to disable
+    probe_health_check_interval: Option<u64>,
+    /// consecutive failed health checks to restart the tunnel
+    probe_health_check_failures: Option<i32>,
+    /// seconds between tries to return to the preferred remote host
+    probe_failback_interval: Option<u64>,
+    runtime_dir: Option<String>,
+    known_hosts_file: Option<String>,
     dynamic_proxy: Option<DynamicProxyConfig>,
     multi_proxy: Option<MultiDynamicProxyConfig>,
+    tunnels: Option<BTreeMap<String, TunnelConfig>>,
+    pac: Option<PacConfig>,
+    router: Option<RouterConfig>,
+    /// the tunnel selected by the current command
+    #[serde(skip)]
+    tunnel: Option<String>,
+    /// values set by env vars and `--set` in order
+    #[serde(skip)]
+    overrides: Vec<overrides::Override>,
+}
+
+/// a named tunnel profile in `[tunnels.<name>]`
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TunnelConfig {
+    Dynamic(DynamicProxyConfig),
+    Multi(MultiDynamicProxyConfig),
+    /// `-L` forwards
+    Local(ForwardConfig),
+    /// `-R` forwards
+    Remote(ForwardConfig),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DynamicProxyConfig {
-    local_addr: String,
+    local_addr: Addr,
+    /// addr of the http proxy front-end forwarding through local_addr
+    http_listen: Option<Addr>,
     remote_user: Option<String>,
-    remote_ip: String,
+    remote_ip: Option<Host>,
     remote_port: Option<usize>,
+    /// `[user@]host[:port]`s tried in order, replaces remote_ip and remote_port
+    remote_hosts: Option<Vec<String>>,
     heart_beat_interval: Option<usize>,
+    #[serde(flatten)]
+    check: CheckConfig,
+    #[serde(flatten)]
+    ssh: SshConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MultiDynamicProxyConfig {
-    local_addr: String,
+    local_addr: Addr,
+    /// addr of the http proxy front-end forwarding through local_addr
+    http_listen: Option<Addr>,
     local_forward_port: Option<usize>,
     remote_user: Option<String>,
-    remote_ip: String,
+    remote_ip: Option<Host>,
     remote_port: Option<usize>,
     heart_beat_interval: Option<usize>,
-    forward_ip: String,
+    forward_ip: Option<Host>,
     forward_port: Option<usize>,
     forward_user: Option<String>,
+    /// jump chain from the first hop to the last, replaces forward_* and remote_*
+    hops: Option<Vec<HopConfig>>,
+    /// one `ssh -J` through the jump hops instead of one ssh per hop
+    proxy_jump: Option<bool>,
+    #[serde(flatten)]
+    check: CheckConfig,
+    #[serde(flatten)]
+    ssh: SshConfig,
+}
+
+/// fixed port forwards to or from the remote, named by the tunnel name
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ForwardConfig {
+    remote_user: Option<String>,
+    remote_ip: Option<Host>,
+    remote_port: Option<usize>,
+    heart_beat_interval: Option<usize>,
+    /// `[bind:]port:host:hostport`s
+    forwards: Vec<String>,
+    /// `check = "none"` to skip checking the forwards
+    #[serde(flatten)]
+    check: CheckConfig,
+    #[serde(flatten)]
+    ssh: SshConfig,
+}
+
+/// `[host]` for ipv6 hosts in `host:port`
+pub fn bracket(host: &str) -> String {
+    if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    }
+}
+
+/// split `host[:port]`, ipv6 hosts with a port are bracketed as `[::1]:1080`
+pub fn split_host_port(s: &str) -> Result<(&str, Option<u16>)> {
+    if let Some(rest) = s.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((host, "")) => Ok((host, None)),
+            Some((host, port)) => match port.strip_prefix(':').map(|e| e.parse::<u16>()) {
+                Some(Ok(port)) => Ok((host, Some(port))),
+                _ => anyhow::bail!("Invalid port in `{}`", s),
+            },
+            None => anyhow::bail!("Unclosed bracket in `{}`", s),
+        };
+    }
+    match s.split_once(':') {
+        // a bare ipv6 has no port
+        Some((_, rest)) if rest.contains(':') => Ok((s, None)),
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) => Ok((host, Some(port))),
+            Err(_) => anyhow::bail!("Invalid port in `{}`", s),
+        },
+        None => Ok((s, None)),
+    }
+}
+
+/// a host name or ip, brackets of ipv6 are removed
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Host(String);
+
+impl std::str::FromStr for Host {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let host = s.trim_start_matches('[').trim_end_matches(']');
+        if host.parse::<std::net::IpAddr>().is_ok()
+            || !host.is_empty()
+                && host
+                    .chars()
+                    .all(|e| e.is_ascii_alphanumeric() || ['-', '.', '_'].contains(&e))
+        {
+            return Ok(Host(host.to_string()));
+        }
+        anyhow::bail!("Invalid host `{}`, need a host name or ip", s)
+    }
+}
+
+impl TryFrom<String> for Host {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Host> for String {
+    fn from(host: Host) -> Self {
+        host.0
+    }
+}
+
+impl Host {
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+/// a `host:port` addr to listen on, kept as `[::1]:1080` for ipv6
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Addr {
+    pub host: Host,
+    pub port: u16,
+    addr: String,
+}
+
+impl std::str::FromStr for Addr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match split_host_port(s)? {
+            (host, Some(port)) => {
+                let host: Host = host.parse()?;
+                let addr = format!("{}:{}", bracket(host.as_str()), port);
+                Ok(Addr { host, port, addr })
+            }
+            _ => anyhow::bail!("Invalid addr `{}`, need `host:port` or `[ipv6]:port`", s),
+        }
+    }
+}
+
+impl TryFrom<String> for Addr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Addr> for String {
+    fn from(addr: Addr) -> Self {
+        addr.addr
+    }
+}
+
+impl Addr {
+    pub fn as_str(&self) -> &str {
+        self.addr.as_str()
+    }
+
+    /// the loopback of the same family as the host
+    pub fn get_loopback(&self) -> &'static str {
+        match self.host.as_str().parse::<std::net::IpAddr>() {
+            Ok(std::net::IpAddr::V6(_)) => "::1",
+            _ => "127.0.0.1",
+        }
+    }
+}
+
+/// a `[bind:]port:host:hostport` forward, bind is the listen side
+#[derive(Debug, Clone, PartialEq)]
+pub struct Forward {
+    pub bind: Option<String>,
+    pub port: u16,
+    pub host: String,
+    pub host_port: u16,
+}
+
+impl std::str::FromStr for Forward {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // split by `:` outside of the brackets of ipv6 addrs
+        let mut parts = Vec::new();
+        let mut part = String::new();
+        let mut bracket = false;
+        for c in s.chars() {
+            match c {
+                '[' => bracket = true,
+                ']' => bracket = false,
+                ':' if !bracket => {
+                    parts.push(std::mem::take(&mut part));
+                    continue;
+                }
+                _ => {}
+            }
+            part.push(c);
+        }
+        parts.push(part);
+        let invalid =
+            || anyhow::anyhow!("Invalid forward `{}`, need `[bind:]port:host:hostport`", s);
+        let (bind, rest) = match parts.len() {
+            3 => (None, &parts[..]),
+            4 => (Some(parts[0].clone()), &parts[1..]),
+            _ => return Err(invalid()),
+        };
+        let host = rest[1].trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Forward {
+            bind: bind.map(|e| e.trim_start_matches('[').trim_end_matches(']').to_string()),
+            port: rest[0].parse().map_err(|_| invalid())?,
+            host: host.to_string(),
+            host_port: rest[2].parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Forward {
+    /// the arg of `-L` or `-R`
+    pub fn spec(&self) -> String {
+        let forward = format!(
+            "{}:{}:{}",
+            self.port,
+            bracket(self.host.as_str()),
+            self.host_port
+        );
+        match self.bind {
+            Some(ref e) => format!("{}:{}", bracket(e), forward),
+            None => forward,
+        }
+    }
+
+    /// host to connect the listen side, the bind addr or localhost if it binds all
+    pub fn get_connect_host(&self) -> &str {
+        match self.bind.as_deref() {
+            None | Some("") | Some("*") | Some("0.0.0.0") | Some("::") => "localhost",
+            Some(e) => e,
+        }
+    }
+}
+
+/// extra options of the ssh command line of a tunnel
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SshConfig {
+    /// `-i`, used for every hop of a multi dynamic proxy
+    identity_file: Option<String>,
+    /// `-F`
+    ssh_config_file: Option<String>,
+    /// Host alias in the ssh config file to reach the remote (the first hop of a multi
+    /// dynamic proxy), remote_user, remote_ip and remote_port are ignored when set
+    ssh_config_host: Option<String>,
+    /// `-o key=value`
+    ssh_options: Option<BTreeMap<String, toml::Value>>,
+    /// `strict`, `accept-new` or `off`, default is `accept-new`
+    host_key_policy: Option<String>,
+    /// pinned `SHA256:...` fingerprint of the remote (the first hop of a multi dynamic proxy)
+    host_key_fingerprint: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostKeyPolicy {
+    /// only connect to hosts recorded in the known_hosts file
+    Strict,
+    /// record unknown hosts, refuse changed keys
+    AcceptNew,
+    /// no host key checking
+    Off,
+}
+
+/// health check of a tunnel, default is connecting www.baidu.com:443 through it
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CheckConfig {
+    /// `none` to only check the socks5 listener
+    check: Option<String>,
+    check_url: Option<String>,
+    /// `host:port`
+    check_host: Option<String>,
+    /// urls or `host:port`s
+    check_targets: Option<Vec<String>>,
+    /// `any` or `all` of the targets must succeed, default is `any`
+    check_mode: Option<String>,
+}
+
+/// a host to connect through the tunnel when checking it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckTarget {
+    pub host: String,
+    pub port: u16,
+}
+
+impl std::str::FromStr for CheckTarget {
+    type Err = anyhow::Error;
+
+    /// parse `scheme://host[:port][/path]` or `host:port`
+    fn from_str(s: &str) -> Result<Self> {
+        let (rest, default_port) = match s.split_once("://") {
+            Some(("http", rest)) => (rest, Some(80)),
+            Some(("https", rest)) => (rest, Some(443)),
+            Some((scheme, _)) => anyhow::bail!("Unsupported check url scheme `{}`", scheme),
+            None => (s, None),
+        };
+        let host_port = rest.split(['/', '?']).next().unwrap_or_default();
+        let (host, port) = match split_host_port(host_port)? {
+            (host, Some(port)) => (host, Some(port)),
+            (host, None) => (host, default_port),
+        };
+        match port {
+            Some(port) if !host.is_empty() => Ok(CheckTarget {
+                host: host.to_string(),
+                port,
+            }),
+            _ => anyhow::bail!("Invalid check target `{}`, need a url or host:port", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckMode {
+    Any,
+    All,
+}
+
+/// the pac file routing hosts to tunnels, served over http
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PacConfig {
+    /// addr of the http server of the pac file, default is `localhost:50080`
+    listen: Option<Addr>,
+    /// tunnel of the hosts matching no rule, default is `direct`
+    default: Option<String>,
+    rules: Option<Vec<RuleConfig>>,
+}
+
+/// route the connections matching all the set conditions to `tunnel`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RuleConfig {
+    /// domain suffix, `example.com` matches itself and its subdomains
+    domain: Option<String>,
+    /// `10.0.0.0/8`
+    cidr: Option<String>,
+    /// target port, not supported in pac
+    port: Option<u16>,
+    /// tunnel name, `direct` for no tunnel
+    tunnel: String,
+}
+
+/// the local socks5 server routing connections to tunnels by rules
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RouterConfig {
+    /// default is `localhost:50000`
+    listen: Option<Addr>,
+    /// tunnel of the connections matching no rule, default is `direct`
+    default: Option<String>,
+    rules: Option<Vec<RuleConfig>>,
+}
+
+/// a resolved routing rule, `tunnel` is None for direct
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub domain: Option<String>,
+    pub cidr: Option<Cidr>,
+    pub port: Option<u16>,
+    pub tunnel: Option<String>,
+}
+
+impl Rule {
+    /// all the set conditions match the target, a cidr only matches ip targets
+    pub fn matches(&self, host: &str, port: u16) -> bool {
+        if let Some(ref e) = self.domain {
+            let host = host.to_ascii_lowercase();
+            if host != *e && !host.ends_with(format!(".{}", e).as_str()) {
+                return false;
+            }
+        }
+        if let Some(ref e) = self.cidr {
+            match host.parse() {
+                Ok(ip) if e.contains(&ip) => {}
+                _ => return false,
+            }
+        }
+        self.port.map(|e| e == port).unwrap_or(true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    pub ip: std::net::IpAddr,
+    pub prefix: u8,
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    /// parse `ip/prefix`, a single ip is a full prefix
+    fn from_str(s: &str) -> Result<Self> {
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (s, None),
+        };
+        let ip: std::net::IpAddr = match ip.parse() {
+            Ok(e) => e,
+            Err(_) => anyhow::bail!("Invalid cidr `{}`", s),
+        };
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix.map(|e| e.parse::<u8>()) {
+            None => max,
+            Some(Ok(e)) if e <= max => e,
+            _ => anyhow::bail!("Invalid cidr `{}`, prefix must be 0-{}", s, max),
+        };
+        Ok(Cidr { ip, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &std::net::IpAddr) -> bool {
+        let (net, ip, bits) = match (self.ip, ip) {
+            (std::net::IpAddr::V4(a), std::net::IpAddr::V4(b)) => {
+                (u32::from(a) as u128, u32::from(*b) as u128, 32)
+            }
+            (std::net::IpAddr::V6(a), std::net::IpAddr::V6(b)) => {
+                (u128::from(a), u128::from(*b), 128)
+            }
+            _ => return false,
+        };
+        if self.prefix == 0 {
+            return true;
+        }
+        let shift = bits - self.prefix as u32;
+        net >> shift == ip >> shift
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HopConfig {
+    ip: Host,
+    user: Option<String>,
+    port: Option<usize>,
+    identity_file: Option<String>,
+    host_key_fingerprint: Option<String>,
+}
+
+/// a resolved hop of the multi dynamic proxy chain
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hop {
+    pub user: String,
+    pub ip: String,
+    pub port: usize,
+    pub identity_file: Option<String>,
+    pub host_key_fingerprint: Option<String>,
+}
+
+impl Hop {
+    /// `user@ip:port` in logs, IPv6 ips in brackets
+    pub fn name(&self) -> String {
+        format!("{}@{}:{}", self.user, bracket(&self.ip), self.port)
+    }
 }
 
 impl Config {
@@ -40,9 +543,19 @@ impl Config {
             .to_string())
     }
 
+    fn expand_home(path: &str) -> String {
+        path.replace('~', Config::get_home_dir().unwrap().as_str())
+    }
+
     pub fn loads(path: Option<&str>) -> Result<Self> {
+        Config::loads_with(path, &[], None)
+    }
+
+    /// load the config file overridden by the `SSHP_*` env vars and then by `sets` of
+    /// `--set key=value`, keys of no section in `sets` are of the tunnel named `tunnel`
+    pub fn loads_with(path: Option<&str>, sets: &[&str], tunnel: Option<&str>) -> Result<Self> {
         let config_path = match path {
-            Some(e) => std::path::PathBuf::from(e.replace('~', Config::get_home_dir()?.as_str())),
+            Some(e) => std::path::PathBuf::from(Config::expand_home(e)),
             None => std::path::PathBuf::from(Config::get_home_dir()?)
                 .join(".config")
                 .join("sshp.toml"),
@@ -53,18 +566,112 @@ impl Config {
                 31,
                 false,
             );
-            std::process::exit(1);
+            std::process::exit(error::EXIT_CONFIG_INVALID);
         }
-        let config: Config = toml::from_str(std::fs::read_to_string(config_path)?.as_str())?;
+        let source = std::fs::read_to_string(config_path)?;
+        let mut config: Config = toml::from_str(source.as_str()).map_err(|e| {
+            SshpError::ConfigInvalid(validate::describe_toml_error(source.as_str(), &e))
+        })?;
+        let mut value: toml::Value = toml::from_str(source.as_str())?;
+        let overrides = overrides::parse(&value, sets, tunnel)?;
+        if !overrides.is_empty() {
+            overrides::apply(&mut value, &overrides)?;
+            config = value.try_into().map_err(|e| {
+                SshpError::ConfigInvalid(format!("Invalid value from env or --set, {}", e))
+            })?;
+            config.overrides = overrides::check(&config, overrides)?;
+        }
+        validate::validate(&mut config, source.as_str())?;
         Ok(config)
     }
 
+    /// select the tunnel the getters work on, `dynamic_proxy` and `multi_proxy`
+    /// refer to the sections with the same name
+    pub fn select_tunnel(&mut self, name: &str) -> Result<()> {
+        if !self.get_tunnel_names().iter().any(|e| e == name) {
+            return Err(SshpError::ConfigInvalid(format!(
+                "Tunnel `{}` not found in config file",
+                name
+            ))
+            .into());
+        }
+        self.tunnel = Some(name.to_string());
+        Ok(())
+    }
+
+    /// the selected tunnel, the `dynamic_proxy` section if none, all the getters of
+    /// sections read it
+    pub fn get_tunnel_name(&self) -> &str {
+        match self.tunnel {
+            Some(ref e) => e.as_str(),
+            None => DYNAMIC_PROXY,
+        }
+    }
+
+    /// all configured tunnel names, including the `dynamic_proxy` and `multi_proxy` sections
+    pub fn get_tunnel_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        if self.dynamic_proxy.is_some() {
+            names.push(DYNAMIC_PROXY.to_string());
+        }
+        if self.multi_proxy.is_some() {
+            names.push(MULTI_PROXY.to_string());
+        }
+        if let Some(ref tunnels) = self.tunnels {
+            names.extend(tunnels.keys().cloned());
+        }
+        names
+    }
+
+    /// type of the selected tunnel, `dynamic`, `multi`, `local` or `remote`
+    pub fn get_tunnel_type(&self) -> &str {
+        match self.get_tunnel_name() {
+            DYNAMIC_PROXY => "dynamic",
+            MULTI_PROXY => "multi",
+            name => match self.get_tunnel(name) {
+                Some(TunnelConfig::Multi(_)) => "multi",
+                Some(TunnelConfig::Local(_)) => "local",
+                Some(TunnelConfig::Remote(_)) => "remote",
+                _ => "dynamic",
+            },
+        }
+    }
+
+    /// the addr naming the state and log files of the selected tunnel, the local
+    /// addr of socks5 tunnels and the tunnel name of forward tunnels
+    pub fn get_tunnel_addr(&self) -> &str {
+        match self.get_tunnel_type() {
+            "multi" => self.get_multi_dynamic_local_addr(),
+            "local" | "remote" => self.get_tunnel_name(),
+            _ => self.get_dynamic_local_addr(),
+        }
+    }
+
+    fn get_tunnel(&self, name: &str) -> Option<&TunnelConfig> {
+        match self.tunnels {
+            Some(ref tunnels) => tunnels.get(name),
+            None => None,
+        }
+    }
+
     pub fn load_dynamic_config(&self) -> &DynamicProxyConfig {
-        if let Some(ref e) = self.dynamic_proxy {
+        let name = self.get_tunnel_name();
+        let config = match name {
+            DYNAMIC_PROXY => self.dynamic_proxy.as_ref(),
+            _ => match self.get_tunnel(name) {
+                Some(TunnelConfig::Dynamic(e)) => Some(e),
+                _ => None,
+            },
+        };
+        if let Some(e) = config {
             return e;
         }
-        utils::print_with_color("Cannot loads config file\n", 31, true);
-        std::process::exit(1);
+        utils::print_with_color(
+            format!("Cannot find dynamic proxy config `{}`\n", name).as_str(),
+            31,
+            true,
+        );
+        std::process::exit(error::EXIT_CONFIG_INVALID);
     }
 
     pub fn get_dynamic_local_addr(&self) -> &str {
@@ -83,7 +690,62 @@ impl Config {
     }
 
     pub fn get_dynamic_remote_ip(&self) -> &str {
-        self.load_dynamic_config().remote_ip.as_str()
+        if let Some(ref e) = self.load_dynamic_config().remote_ip {
+            return e.as_str();
+        }
+        utils::print_with_color(
+            "dynamic proxy needs `remote_ip` or `ssh_config_host`\n",
+            31,
+            true,
+        );
+        std::process::exit(error::EXIT_CONFIG_INVALID);
+    }
+
+    /// remote hosts in order of preference, `remote_hosts` or the single remote
+    pub fn get_dynamic_remote_hosts(&self) -> Result<Vec<Hop>> {
+        let config = self.load_dynamic_config();
+        let hosts = match config.remote_hosts {
+            Some(ref e) if !e.is_empty() => e,
+            _ => {
+                return Ok(vec![Hop {
+                    user: self.get_dynamic_remote_user().to_string(),
+                    ip: self.get_dynamic_remote_ip().to_string(),
+                    port: self.get_dynamic_remote_port(),
+                    identity_file: None,
+                    host_key_fingerprint: None,
+                }])
+            }
+        };
+        let mut res = Vec::new();
+        for e in hosts {
+            let (user, host) = match e.split_once('@') {
+                Some((user, host)) => (user, host),
+                None => (self.get_dynamic_remote_user(), e.as_str()),
+            };
+            let invalid = || {
+                SshpError::ConfigInvalid(format!(
+                    "Invalid remote_hosts `{}`, need `[user@]host[:port]`, ipv6 with a port as `[::1]:22`",
+                    e
+                ))
+            };
+            let (ip, port) = match split_host_port(host) {
+                Ok((ip, port)) => match ip.parse::<Host>() {
+                    Ok(ip) => (ip, port),
+                    Err(_) => return Err(invalid().into()),
+                },
+                Err(_) => return Err(invalid().into()),
+            };
+            res.push(Hop {
+                user: user.to_string(),
+                ip: ip.into(),
+                port: port
+                    .map(|e| e as usize)
+                    .unwrap_or(self.get_dynamic_remote_port()),
+                identity_file: None,
+                host_key_fingerprint: None,
+            });
+        }
+        Ok(res)
     }
 
     pub fn get_dynamic_heart_beat_interval(&self) -> usize {
@@ -91,55 +753,472 @@ impl Config {
     }
 
     pub fn load_multi_dynamic_config(&self) -> &MultiDynamicProxyConfig {
-        if let Some(ref e) = self.multi_proxy {
+        let name = self.get_tunnel_name();
+        let config = match name {
+            MULTI_PROXY => self.multi_proxy.as_ref(),
+            _ => match self.get_tunnel(name) {
+                Some(TunnelConfig::Multi(e)) => Some(e),
+                _ => None,
+            },
+        };
+        if let Some(e) = config {
             return e;
         }
-        utils::print_with_color("Cannot loads config file\n", 31, false);
-        std::process::exit(1);
+        utils::print_with_color(
+            format!("Cannot find multi dynamic proxy config `{}`\n", name).as_str(),
+            31,
+            false,
+        );
+        std::process::exit(error::EXIT_CONFIG_INVALID);
     }
 
     pub fn get_multi_dynamic_local_addr(&self) -> &str {
         self.load_multi_dynamic_config().local_addr.as_str()
     }
 
-    pub fn get_multi_dynamic_remote_user(&self) -> &str {
-        match &self.load_multi_dynamic_config().remote_user {
-            Some(e) => e.as_str(),
-            None => "root",
+    pub fn get_multi_dynamic_heart_beat_interval(&self) -> usize {
+        self.load_multi_dynamic_config()
+            .heart_beat_interval
+            .unwrap_or(60)
+    }
+
+    /// hops from the first jump host to the last one which opens the dynamic proxy,
+    /// `forward_*` and `remote_*` make a chain of two hops when `hops` is not set
+    pub fn get_multi_dynamic_hops(&self) -> Vec<Hop> {
+        let config = self.load_multi_dynamic_config();
+        if let Some(ref hops) = config.hops {
+            if hops.len() >= 2 {
+                return hops
+                    .iter()
+                    .map(|e| Hop {
+                        user: e.user.clone().unwrap_or_else(|| "root".to_string()),
+                        ip: e.ip.clone().into(),
+                        port: e.port.unwrap_or(22),
+                        identity_file: e.identity_file.as_deref().map(Config::expand_home),
+                        host_key_fingerprint: e.host_key_fingerprint.clone(),
+                    })
+                    .collect();
+            }
+            utils::print_with_color("hops needs at least 2 hosts\n", 31, false);
+            std::process::exit(error::EXIT_CONFIG_INVALID);
+        }
+        if let (Some(forward_ip), Some(remote_ip)) = (&config.forward_ip, &config.remote_ip) {
+            return vec![
+                Hop {
+                    user: config
+                        .forward_user
+                        .clone()
+                        .unwrap_or_else(|| "root".to_string()),
+                    ip: forward_ip.clone().into(),
+                    port: config.forward_port.unwrap_or(22),
+                    identity_file: None,
+                    host_key_fingerprint: None,
+                },
+                Hop {
+                    user: config
+                        .remote_user
+                        .clone()
+                        .unwrap_or_else(|| "root".to_string()),
+                    ip: remote_ip.clone().into(),
+                    port: config.remote_port.unwrap_or(22),
+                    identity_file: None,
+                    host_key_fingerprint: None,
+                },
+            ];
         }
+        utils::print_with_color(
+            "multi proxy needs `hops` or both `forward_ip` and `remote_ip`\n",
+            31,
+            false,
+        );
+        std::process::exit(error::EXIT_CONFIG_INVALID);
     }
 
-    pub fn get_multi_dynamic_remote_port(&self) -> usize {
-        self.load_multi_dynamic_config().remote_port.unwrap_or(22)
+    pub fn get_multi_dynamic_proxy_jump(&self) -> bool {
+        self.load_multi_dynamic_config().proxy_jump.unwrap_or(false)
     }
 
-    pub fn get_multi_dynamic_remote_ip(&self) -> &str {
-        self.load_multi_dynamic_config().remote_ip.as_str()
+    pub fn get_multi_dynamic_local_forward_port(&self) -> Option<usize> {
+        self.load_multi_dynamic_config().local_forward_port
     }
 
-    pub fn get_multi_dynamic_heart_beat_interval(&self) -> usize {
-        self.load_multi_dynamic_config()
-            .heart_beat_interval
-            .unwrap_or(60)
+    /// addr of the http proxy front-end of the selected tunnel
+    pub fn get_http_listen(&self) -> Option<&str> {
+        match self.get_tunnel_type() {
+            "multi" => self
+                .load_multi_dynamic_config()
+                .http_listen
+                .as_ref()
+                .map(|e| e.as_str()),
+            "local" | "remote" => None,
+            _ => self
+                .load_dynamic_config()
+                .http_listen
+                .as_ref()
+                .map(|e| e.as_str()),
+        }
+    }
+
+    pub fn load_forward_config(&self) -> &ForwardConfig {
+        let name = self.get_tunnel_name();
+        match self.get_tunnel(name) {
+            Some(TunnelConfig::Local(e)) | Some(TunnelConfig::Remote(e)) => e,
+            _ => {
+                utils::print_with_color(
+                    format!("Cannot find forward tunnel config `{}`\n", name).as_str(),
+                    31,
+                    true,
+                );
+                std::process::exit(error::EXIT_CONFIG_INVALID);
+            }
+        }
     }
 
-    pub fn get_multi_dynamic_forward_user(&self) -> &str {
-        match &self.load_multi_dynamic_config().forward_user {
+    pub fn get_forward_remote_user(&self) -> &str {
+        match &self.load_forward_config().remote_user {
             Some(e) => e.as_str(),
             None => "root",
         }
     }
 
-    pub fn get_multi_dynamic_forward_port(&self) -> usize {
-        self.load_multi_dynamic_config().forward_port.unwrap_or(22)
+    pub fn get_forward_remote_ip(&self) -> &str {
+        if let Some(ref e) = self.load_forward_config().remote_ip {
+            return e.as_str();
+        }
+        utils::print_with_color(
+            "forward tunnel needs `remote_ip` or `ssh_config_host`\n",
+            31,
+            true,
+        );
+        std::process::exit(error::EXIT_CONFIG_INVALID);
     }
 
-    pub fn get_multi_dynamic_forward_ip(&self) -> &str {
-        self.load_multi_dynamic_config().forward_ip.as_str()
+    pub fn get_forward_remote_port(&self) -> usize {
+        self.load_forward_config().remote_port.unwrap_or(22)
     }
 
-    pub fn get_multi_dynamic_local_forward_port(&self) -> Option<usize> {
-        self.load_multi_dynamic_config().local_forward_port
+    pub fn get_forward_heart_beat_interval(&self) -> usize {
+        self.load_forward_config().heart_beat_interval.unwrap_or(60)
+    }
+
+    pub fn get_forwards(&self) -> Result<Vec<Forward>> {
+        let forwards = &self.load_forward_config().forwards;
+        if forwards.is_empty() {
+            return Err(SshpError::ConfigInvalid(format!(
+                "Tunnel `{}` needs `forwards`",
+                self.get_tunnel_name()
+            ))
+            .into());
+        }
+        forwards.iter().map(|e| e.parse()).collect()
+    }
+
+    /// the remote of a forward tunnel in the ssh command line
+    pub fn get_forward_target_args(&self) -> Vec<String> {
+        match self.get_ssh_config_host() {
+            Some(host) => vec![host.to_string()],
+            None => vec![
+                format!(
+                    "{}@{}",
+                    self.get_forward_remote_user(),
+                    self.get_forward_remote_ip()
+                ),
+                "-p".to_string(),
+                self.get_forward_remote_port().to_string(),
+            ],
+        }
+    }
+
+    fn load_check_config(&self) -> &CheckConfig {
+        match self.get_tunnel_type() {
+            "multi" => &self.load_multi_dynamic_config().check,
+            "local" | "remote" => &self.load_forward_config().check,
+            _ => &self.load_dynamic_config().check,
+        }
+    }
+
+    /// targets to connect through the tunnel, empty if `check = "none"`
+    pub fn get_check_targets(&self) -> Result<Vec<CheckTarget>> {
+        let config = self.load_check_config();
+        if config.check.as_deref() == Some("none") {
+            return Ok(Vec::new());
+        }
+        let mut targets = Vec::new();
+        if let Some(ref e) = config.check_url {
+            targets.push(e.parse()?);
+        }
+        if let Some(ref e) = config.check_host {
+            targets.push(e.parse()?);
+        }
+        if let Some(ref e) = config.check_targets {
+            for x in e {
+                targets.push(x.parse()?);
+            }
+        }
+        if targets.is_empty() {
+            targets.push(CheckTarget {
+                host: "www.baidu.com".to_string(),
+                port: 443,
+            });
+        }
+        Ok(targets)
+    }
+
+    pub fn get_check_mode(&self) -> Result<CheckMode> {
+        match self.load_check_config().check_mode.as_deref() {
+            None | Some("any") => Ok(CheckMode::Any),
+            Some("all") => Ok(CheckMode::All),
+            Some(e) => Err(SshpError::ConfigInvalid(format!(
+                "Invalid check_mode `{}`, need `any` or `all`",
+                e
+            ))
+            .into()),
+        }
+    }
+
+    /// socks5 listen addr of the tunnel named `name` without selecting it
+    pub fn get_tunnel_local_addr(&self, name: &str) -> Result<&str> {
+        let addr = match name {
+            DYNAMIC_PROXY => self.dynamic_proxy.as_ref().map(|e| e.local_addr.as_str()),
+            MULTI_PROXY => self.multi_proxy.as_ref().map(|e| e.local_addr.as_str()),
+            _ => match self.get_tunnel(name) {
+                Some(TunnelConfig::Dynamic(e)) => Some(e.local_addr.as_str()),
+                Some(TunnelConfig::Multi(e)) => Some(e.local_addr.as_str()),
+                Some(_) => {
+                    return Err(SshpError::ConfigInvalid(format!(
+                        "Tunnel `{}` is not a socks5 tunnel",
+                        name
+                    ))
+                    .into())
+                }
+                None => None,
+            },
+        };
+        match addr {
+            Some(e) => Ok(e),
+            None => Err(SshpError::ConfigInvalid(format!(
+                "Tunnel `{}` not found in config file",
+                name
+            ))
+            .into()),
+        }
+    }
+
+    /// `direct` is None, the others must be configured tunnels
+    fn parse_rule_tunnel(&self, name: &str) -> Result<Option<String>> {
+        if name == "direct" {
+            return Ok(None);
+        }
+        self.get_tunnel_local_addr(name)?;
+        Ok(Some(name.to_string()))
+    }
+
+    fn load_pac_config(&self) -> Result<&PacConfig> {
+        match self.pac {
+            Some(ref e) => Ok(e),
+            None => {
+                Err(SshpError::ConfigInvalid("Cannot find [pac] in config file".to_string()).into())
+            }
+        }
+    }
+
+    pub fn get_pac_listen(&self) -> Result<&str> {
+        Ok(self
+            .load_pac_config()?
+            .listen
+            .as_ref()
+            .map(|e| e.as_str())
+            .unwrap_or("localhost:50080"))
+    }
+
+    /// tunnel of the hosts matching no rule, None for direct
+    pub fn get_pac_default(&self) -> Result<Option<String>> {
+        self.parse_rule_tunnel(
+            self.load_pac_config()?
+                .default
+                .as_deref()
+                .unwrap_or("direct"),
+        )
+    }
+
+    /// rules of the pac file in order, the first matched one wins
+    pub fn get_pac_rules(&self) -> Result<Vec<Rule>> {
+        let rules = self.parse_rules("pac", &self.load_pac_config()?.rules)?;
+        if let Some(i) = rules.iter().position(|e| e.port.is_some()) {
+            return Err(SshpError::ConfigInvalid(format!(
+                "pac rule {} has `port`, which pac does not support",
+                i + 1
+            ))
+            .into());
+        }
+        Ok(rules)
+    }
+
+    fn parse_rules(&self, section: &str, rules: &Option<Vec<RuleConfig>>) -> Result<Vec<Rule>> {
+        let mut res = Vec::new();
+        for (i, e) in rules.iter().flatten().enumerate() {
+            if e.domain.is_none() && e.cidr.is_none() && e.port.is_none() {
+                return Err(SshpError::ConfigInvalid(format!(
+                    "{} rule {} needs `domain`, `cidr` or `port`",
+                    section,
+                    i + 1
+                ))
+                .into());
+            }
+            res.push(Rule {
+                domain: e
+                    .domain
+                    .as_ref()
+                    .map(|e| e.trim_start_matches('.').to_ascii_lowercase()),
+                cidr: match e.cidr {
+                    Some(ref e) => Some(e.parse()?),
+                    None => None,
+                },
+                port: e.port,
+                tunnel: self.parse_rule_tunnel(e.tunnel.as_str())?,
+            });
+        }
+        Ok(res)
+    }
+
+    fn load_router_config(&self) -> Result<&RouterConfig> {
+        match self.router {
+            Some(ref e) => Ok(e),
+            None => Err(SshpError::ConfigInvalid(
+                "Cannot find [router] in config file".to_string(),
+            )
+            .into()),
+        }
+    }
+
+    pub fn get_router_listen(&self) -> Result<&str> {
+        Ok(self
+            .load_router_config()?
+            .listen
+            .as_ref()
+            .map(|e| e.as_str())
+            .unwrap_or("localhost:50000"))
+    }
+
+    /// tunnel of the connections matching no rule, None for direct
+    pub fn get_router_default(&self) -> Result<Option<String>> {
+        self.parse_rule_tunnel(
+            self.load_router_config()?
+                .default
+                .as_deref()
+                .unwrap_or("direct"),
+        )
+    }
+
+    /// rules of the router in order, the first matched one wins
+    pub fn get_router_rules(&self) -> Result<Vec<Rule>> {
+        self.parse_rules("router", &self.load_router_config()?.rules)
+    }
+
+    /// dir of the state and log files, `runtime_dir` in config, `$XDG_RUNTIME_DIR/sshp`
+    /// or `~/.local/state/sshp`
+    pub fn get_runtime_dir(&self) -> std::path::PathBuf {
+        if let Some(ref e) = self.runtime_dir {
+            return std::path::PathBuf::from(Config::expand_home(e));
+        }
+        match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(e) if !e.is_empty() => std::path::PathBuf::from(e).join("sshp"),
+            _ => std::path::PathBuf::from(Config::get_home_dir().unwrap())
+                .join(".local")
+                .join("state")
+                .join("sshp"),
+        }
+    }
+
+    fn load_ssh_config(&self) -> &SshConfig {
+        match self.get_tunnel_type() {
+            "multi" => &self.load_multi_dynamic_config().ssh,
+            "local" | "remote" => &self.load_forward_config().ssh,
+            _ => &self.load_dynamic_config().ssh,
+        }
+    }
+
+    pub fn get_ssh_config_host(&self) -> Option<&str> {
+        self.load_ssh_config().ssh_config_host.as_deref()
+    }
+
+    /// `-F`, `-i` and `-o` args from the ssh config of the selected tunnel, they must go
+    /// before the default `-o` args as ssh uses the first obtained value
+    pub fn get_ssh_extra_args(&self) -> Vec<String> {
+        let config = self.load_ssh_config();
+        let mut args = Vec::new();
+        if let Some(ref e) = config.ssh_config_file {
+            args.extend(vec!["-F".to_string(), Config::expand_home(e)]);
+        }
+        if let Some(ref e) = config.identity_file {
+            args.extend(vec!["-i".to_string(), Config::expand_home(e)]);
+        }
+        if let Some(ref options) = config.ssh_options {
+            for (k, v) in options {
+                let v = match v {
+                    toml::Value::String(e) => e.to_string(),
+                    e => e.to_string(),
+                };
+                args.extend(vec!["-o".to_string(), format!("{}={}", k, v)]);
+            }
+        }
+        args
+    }
+
+    pub fn get_host_key_policy(&self) -> Result<HostKeyPolicy> {
+        match self.load_ssh_config().host_key_policy.as_deref() {
+            None | Some("accept-new") => Ok(HostKeyPolicy::AcceptNew),
+            Some("strict") => Ok(HostKeyPolicy::Strict),
+            Some("off") => Ok(HostKeyPolicy::Off),
+            Some(e) => Err(SshpError::ConfigInvalid(format!(
+                "Invalid host_key_policy `{}`, need `strict`, `accept-new` or `off`",
+                e
+            ))
+            .into()),
+        }
+    }
+
+    pub fn get_host_key_fingerprint(&self) -> Option<&str> {
+        self.load_ssh_config().host_key_fingerprint.as_deref()
+    }
+
+    /// the known_hosts file of sshp, default is `~/.config/sshp/known_hosts`
+    pub fn get_known_hosts_file(&self) -> std::path::PathBuf {
+        match self.known_hosts_file {
+            Some(ref e) => std::path::PathBuf::from(Config::expand_home(e)),
+            None => std::path::PathBuf::from(Config::get_home_dir().unwrap())
+                .join(".config")
+                .join("sshp")
+                .join("known_hosts"),
+        }
+    }
+
+    /// `-o` args of host key checking, `alias` is the known_hosts name of a host reached
+    /// through a local forward
+    pub fn get_host_key_args(&self, alias: Option<String>) -> Result<Vec<String>> {
+        let checking = match self.get_host_key_policy()? {
+            HostKeyPolicy::Off => {
+                return Ok(vec![
+                    "-o".to_string(),
+                    "StrictHostKeyChecking=no".to_string(),
+                ])
+            }
+            HostKeyPolicy::Strict => "yes",
+            HostKeyPolicy::AcceptNew => "accept-new",
+        };
+        let mut args = vec![
+            "-o".to_string(),
+            format!("StrictHostKeyChecking={}", checking),
+            "-o".to_string(),
+            format!(
+                "UserKnownHostsFile={}",
+                self.get_known_hosts_file().to_string_lossy()
+            ),
+        ];
+        if let Some(e) = alias {
+            args.extend(vec!["-o".to_string(), format!("HostKeyAlias={}", e)]);
+        }
+        Ok(args)
     }
 
     pub fn get_probe_check_interval(&self) -> i32 {
@@ -155,4 +1234,247 @@ impl Config {
         }
         3600
     }
+
+    pub fn get_stop_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.stop_timeout.unwrap_or(5))
+    }
+
+    pub fn get_startup_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.startup_timeout.unwrap_or(30))
+    }
+
+    pub fn get_probe_backoff_initial(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.probe_backoff_initial.unwrap_or(5))
+    }
+
+    pub fn get_probe_backoff_max(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.probe_backoff_max.unwrap_or(300))
+    }
+
+    pub fn get_probe_backoff_multiplier(&self) -> f64 {
+        self.probe_backoff_multiplier.unwrap_or(2.0).max(1.0)
+    }
+
+    pub fn get_probe_backoff_jitter(&self) -> f64 {
+        self.probe_backoff_jitter.unwrap_or(0.2).clamp(0.0, 1.0)
+    }
+
+    pub fn get_probe_stable_period(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.probe_stable_period.unwrap_or(60))
+    }
+
+    pub fn get_probe_health_check_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.probe_health_check_interval.unwrap_or(60))
+    }
+
+    pub fn get_probe_health_check_failures(&self) -> i32 {
+        self.probe_health_check_failures.unwrap_or(3).max(1)
+    }
+
+    pub fn get_probe_failback_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.probe_failback_interval.unwrap_or(300))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::{Addr, CheckMode, CheckTarget, Cidr, Config, Forward};
+
+    #[test]
+    fn test_select_tunnel() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [dynamic_proxy]
+            local_addr = "localhost:50001"
+            remote_ip = "192.168.8.22"
+
+            [tunnels.staging]
+            type = "dynamic"
+            local_addr = "localhost:50011"
+            remote_ip = "10.0.0.1"
+            remote_user = "ops"
+
+            [tunnels.partner]
+            type = "multi"
+            local_addr = "localhost:50012"
+            remote_ip = "10.1.0.1"
+            forward_ip = "10.2.0.1"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_tunnel_names(),
+            vec!["dynamic_proxy", "partner", "staging"]
+        );
+        assert_eq!(config.get_dynamic_local_addr(), "localhost:50001");
+        config.select_tunnel("staging").unwrap();
+        assert_eq!(config.get_dynamic_local_addr(), "localhost:50011");
+        assert_eq!(config.get_dynamic_remote_user(), "ops");
+        config.select_tunnel("partner").unwrap();
+        let hops = config.get_multi_dynamic_hops();
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[0].ip, "10.2.0.1");
+        assert_eq!(hops[1].ip, "10.1.0.1");
+        assert!(config.select_tunnel("prod").is_err());
+    }
+
+    #[test]
+    fn test_ssh_extra_args() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [tunnels.alias]
+            type = "dynamic"
+            local_addr = "localhost:50011"
+            ssh_config_host = "bastion"
+            ssh_config_file = "/etc/sshp/ssh_config"
+            identity_file = "/keys/id_ed25519"
+            ssh_options = { IdentitiesOnly = "yes", ConnectTimeout = 10 }
+            "#,
+        )
+        .unwrap();
+        config.select_tunnel("alias").unwrap();
+        assert_eq!(config.get_ssh_config_host(), Some("bastion"));
+        assert_eq!(
+            config.get_ssh_extra_args(),
+            vec![
+                "-F",
+                "/etc/sshp/ssh_config",
+                "-i",
+                "/keys/id_ed25519",
+                "-o",
+                "ConnectTimeout=10",
+                "-o",
+                "IdentitiesOnly=yes"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_targets() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [tunnels.default]
+            type = "dynamic"
+            local_addr = "localhost:50011"
+            remote_ip = "10.0.0.1"
+
+            [tunnels.none]
+            type = "dynamic"
+            local_addr = "localhost:50012"
+            remote_ip = "10.0.0.1"
+            check = "none"
+
+            [tunnels.internal]
+            type = "multi"
+            local_addr = "localhost:50013"
+            remote_ip = "10.1.0.1"
+            forward_ip = "10.2.0.1"
+            check_url = "http://wiki.internal/index.html"
+            check_targets = ["10.1.0.2:22", "https://git.internal"]
+            check_mode = "all"
+            "#,
+        )
+        .unwrap();
+        config.select_tunnel("default").unwrap();
+        assert_eq!(
+            config.get_check_targets().unwrap(),
+            vec![CheckTarget {
+                host: "www.baidu.com".to_string(),
+                port: 443
+            }]
+        );
+        assert_eq!(config.get_check_mode().unwrap(), CheckMode::Any);
+        config.select_tunnel("none").unwrap();
+        assert!(config.get_check_targets().unwrap().is_empty());
+        config.select_tunnel("internal").unwrap();
+        let targets = config
+            .get_check_targets()
+            .unwrap()
+            .into_iter()
+            .map(|e| format!("{}:{}", e.host, e.port))
+            .collect::<Vec<String>>();
+        assert_eq!(
+            targets,
+            vec!["wiki.internal:80", "10.1.0.2:22", "git.internal:443"]
+        );
+        assert_eq!(config.get_check_mode().unwrap(), CheckMode::All);
+        assert!(config.get_ssh_extra_args().is_empty());
+        assert!("ftp://a".parse::<CheckTarget>().is_err());
+        assert!("a".parse::<CheckTarget>().is_err());
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"11.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains(&"::1".parse().unwrap()));
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(&"2001:db8::1".parse().unwrap()));
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(&"8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_dynamic_remote_hosts() {
+        let config: Config = toml::from_str(
+            r#"
+            [dynamic_proxy]
+            local_addr = "localhost:50001"
+            remote_user = "ops"
+            remote_port = 2222
+            remote_hosts = ["10.0.0.1", "root@10.0.0.2:22", "2001:db8::1", "[2001:db8::2]:22"]
+            "#,
+        )
+        .unwrap();
+        let hosts = config.get_dynamic_remote_hosts().unwrap();
+        assert_eq!(
+            hosts.iter().map(|e| e.name()).collect::<Vec<String>>(),
+            vec![
+                "ops@10.0.0.1:2222",
+                "root@10.0.0.2:22",
+                "ops@[2001:db8::1]:2222",
+                "ops@[2001:db8::2]:22"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forward() {
+        let forward: Forward = "5432:db.internal:5432".parse().unwrap();
+        assert_eq!(forward.bind, None);
+        assert_eq!(forward.get_connect_host(), "localhost");
+        assert_eq!(forward.spec(), "5432:db.internal:5432");
+        let forward: Forward = "[::1]:8080:[2001:db8::1]:80".parse().unwrap();
+        assert_eq!(forward.bind.as_deref(), Some("::1"));
+        assert_eq!(forward.host, "2001:db8::1");
+        assert_eq!(forward.spec(), "[::1]:8080:[2001:db8::1]:80");
+        assert!("5432:db.internal".parse::<Forward>().is_err());
+    }
+
+    #[test]
+    fn test_addr() {
+        let addr: Addr = "[::1]:1080".parse().unwrap();
+        assert_eq!(addr.host.as_str(), "::1");
+        assert_eq!(addr.port, 1080);
+        assert_eq!(addr.as_str(), "[::1]:1080");
+        assert_eq!(addr.get_loopback(), "::1");
+        let addr: Addr = "localhost:1080".parse().unwrap();
+        assert_eq!(addr.as_str(), "localhost:1080");
+        assert_eq!(addr.get_loopback(), "127.0.0.1");
+        assert!("::1:1080".parse::<Addr>().is_err());
+        assert!("localhost".parse::<Addr>().is_err());
+        assert!("local host:1080".parse::<Addr>().is_err());
+        let err = toml::from_str::<Config>(
+            r#"
+            [dynamic_proxy]
+            local_addr = "localhost"
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("dynamic_proxy.local_addr"));
+    }
 }
@@ -0,0 +1,359 @@
+use crate::cfg::{Config, DYNAMIC_PROXY, MULTI_PROXY};
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use toml::Value;
+
+/// where an effective value comes from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    File,
+    Default,
+    /// `SSHP_*` env vars
+    Env,
+    /// `--set key=value`
+    Cli,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Source::File => write!(f, "file"),
+            Source::Default => write!(f, "default"),
+            Source::Env => write!(f, "env"),
+            Source::Cli => write!(f, "cli"),
+        }
+    }
+}
+
+/// an effective value and its source
+#[derive(Debug, Serialize)]
+pub struct Setting {
+    pub value: Value,
+    pub source: Source,
+}
+
+/// a table of the effective config, `path` is empty for the top level
+#[derive(Debug)]
+pub struct Section {
+    pub path: Vec<String>,
+    pub settings: BTreeMap<String, Setting>,
+}
+
+/// the values set in the file or overridden, replaced by the resolved ones, which are
+/// defaults if not set
+fn section(
+    config: &Config,
+    path: Vec<String>,
+    file: Value,
+    resolved: Vec<(&str, Value)>,
+) -> Section {
+    let mut settings = BTreeMap::new();
+    if let Value::Table(table) = file {
+        for (k, v) in table {
+            settings.insert(
+                k,
+                Setting {
+                    value: v,
+                    source: Source::File,
+                },
+            );
+        }
+    }
+    for (k, v) in resolved {
+        let source = match settings.get(k) {
+            Some(_) => Source::File,
+            None => Source::Default,
+        };
+        settings.insert(k.to_string(), Setting { value: v, source });
+    }
+    // the last override of a key wins
+    for e in config.overrides.iter() {
+        if e.section == path {
+            if let Some(x) = settings.get_mut(e.key[0].as_str()) {
+                x.source = e.source;
+            }
+        }
+    }
+    Section { path, settings }
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+fn secs(d: std::time::Duration) -> Value {
+    Value::Integer(d.as_secs() as i64)
+}
+
+fn global_section(config: &Config) -> Result<Section> {
+    let mut file = Value::try_from(config)?;
+    if let Value::Table(ref mut table) = file {
+        for e in [DYNAMIC_PROXY, MULTI_PROXY, "tunnels", "pac", "router"] {
+            table.remove(e);
+        }
+    }
+    let resolved = vec![
+        (
+            "probe_check_interval",
+            Value::Integer(config.get_probe_check_interval() as i64),
+        ),
+        (
+            "probe_failed_times_when_exit",
+            Value::Integer(config.get_probe_failed_times_when_exit() as i64),
+        ),
+        ("stop_timeout", secs(config.get_stop_timeout())),
+        ("startup_timeout", secs(config.get_startup_timeout())),
+        (
+            "probe_backoff_initial",
+            secs(config.get_probe_backoff_initial()),
+        ),
+        ("probe_backoff_max", secs(config.get_probe_backoff_max())),
+        (
+            "probe_backoff_multiplier",
+            Value::Float(config.get_probe_backoff_multiplier()),
+        ),
+        (
+            "probe_backoff_jitter",
+            Value::Float(config.get_probe_backoff_jitter()),
+        ),
+        (
+            "probe_stable_period",
+            secs(config.get_probe_stable_period()),
+        ),
+        (
+            "probe_health_check_interval",
+            secs(config.get_probe_health_check_interval()),
+        ),
+        (
+            "probe_health_check_failures",
+            Value::Integer(config.get_probe_health_check_failures() as i64),
+        ),
+        (
+            "probe_failback_interval",
+            secs(config.get_probe_failback_interval()),
+        ),
+        (
+            "runtime_dir",
+            string(&config.get_runtime_dir().to_string_lossy()),
+        ),
+        (
+            "known_hosts_file",
+            string(&config.get_known_hosts_file().to_string_lossy()),
+        ),
+    ];
+    Ok(section(config, Vec::new(), file, resolved))
+}
+
+/// the section of the selected tunnel
+fn tunnel_section(config: &Config) -> Result<Section> {
+    let name = config.get_tunnel_name();
+    let (path, file) = match name {
+        DYNAMIC_PROXY => (
+            vec![name.to_string()],
+            Value::try_from(&config.dynamic_proxy)?,
+        ),
+        MULTI_PROXY => (
+            vec![name.to_string()],
+            Value::try_from(&config.multi_proxy)?,
+        ),
+        _ => (
+            vec!["tunnels".to_string(), name.to_string()],
+            Value::try_from(config.get_tunnel(name))?,
+        ),
+    };
+    let has = |key: &str| file.get(key).is_some();
+    let mut resolved = Vec::new();
+    match config.get_tunnel_type() {
+        "multi" => {
+            // the hops replace the forward_* and remote_* settings, the forward one is first
+            if !has("hops") {
+                let hops = config.get_multi_dynamic_hops();
+                resolved.extend(vec![
+                    ("forward_user", string(&hops[0].user)),
+                    ("forward_port", Value::Integer(hops[0].port as i64)),
+                    ("remote_user", string(&hops[1].user)),
+                    ("remote_port", Value::Integer(hops[1].port as i64)),
+                ]);
+            }
+            resolved.extend(vec![
+                (
+                    "heart_beat_interval",
+                    Value::Integer(config.get_multi_dynamic_heart_beat_interval() as i64),
+                ),
+                (
+                    "proxy_jump",
+                    Value::Boolean(config.get_multi_dynamic_proxy_jump()),
+                ),
+            ]);
+        }
+        "local" | "remote" => resolved.extend(vec![
+            ("remote_user", string(config.get_forward_remote_user())),
+            (
+                "remote_port",
+                Value::Integer(config.get_forward_remote_port() as i64),
+            ),
+            (
+                "heart_beat_interval",
+                Value::Integer(config.get_forward_heart_beat_interval() as i64),
+            ),
+        ]),
+        _ => resolved.extend(vec![
+            ("remote_user", string(config.get_dynamic_remote_user())),
+            (
+                "remote_port",
+                Value::Integer(config.get_dynamic_remote_port() as i64),
+            ),
+            (
+                "heart_beat_interval",
+                Value::Integer(config.get_dynamic_heart_beat_interval() as i64),
+            ),
+        ]),
+    }
+    if matches!(config.get_tunnel_type(), "dynamic" | "multi") {
+        if ["check", "check_url", "check_host", "check_targets"]
+            .iter()
+            .all(|e| !has(e))
+        {
+            let targets = config
+                .get_check_targets()?
+                .iter()
+                .map(|e| string(&format!("{}:{}", e.host, e.port)))
+                .collect();
+            resolved.push(("check_targets", Value::Array(targets)));
+        }
+        let mode = file
+            .get("check_mode")
+            .and_then(|e| e.as_str())
+            .unwrap_or("any");
+        resolved.push(("check_mode", string(mode)));
+    }
+    let policy = file
+        .get("host_key_policy")
+        .and_then(|e| e.as_str())
+        .unwrap_or("accept-new");
+    resolved.push(("host_key_policy", string(policy)));
+    Ok(section(config, path, file, resolved))
+}
+
+/// the effective config of `tunnel`, or of all the tunnels with `[pac]` and `[router]`
+pub fn sections(config: &mut Config, tunnel: Option<&str>) -> Result<Vec<Section>> {
+    let selected = config.tunnel.take();
+    let res: Result<Vec<Section>> = (|| {
+        let mut sections = vec![global_section(config)?];
+        let names = match tunnel {
+            Some(e) => vec![e.to_string()],
+            None => config.get_tunnel_names(),
+        };
+        for name in names {
+            config.select_tunnel(name.as_str())?;
+            sections.push(tunnel_section(config)?);
+        }
+        if tunnel.is_some() {
+            return Ok(sections);
+        }
+        if let Some(ref pac) = config.pac {
+            let resolved = vec![
+                ("listen", string(config.get_pac_listen()?)),
+                (
+                    "default",
+                    string(pac.default.as_deref().unwrap_or("direct")),
+                ),
+            ];
+            sections.push(section(
+                config,
+                vec!["pac".to_string()],
+                Value::try_from(pac)?,
+                resolved,
+            ));
+        }
+        if let Some(ref router) = config.router {
+            let resolved = vec![
+                ("listen", string(config.get_router_listen()?)),
+                (
+                    "default",
+                    string(router.default.as_deref().unwrap_or("direct")),
+                ),
+            ];
+            sections.push(section(
+                config,
+                vec!["router".to_string()],
+                Value::try_from(router)?,
+                resolved,
+            ));
+        }
+        Ok(sections)
+    })();
+    config.tunnel = selected;
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::effective::{sections, Source};
+    use crate::cfg::Config;
+    use toml::Value;
+
+    #[test]
+    fn test_sections() {
+        let mut config: Config = toml::from_str(
+            r#"
+            probe_check_interval = 10
+
+            [tunnels.staging]
+            type = "dynamic"
+            local_addr = "[::1]:50001"
+            remote_ip = "10.0.0.1"
+            ssh_options = { ConnectTimeout = 5 }
+
+            [multi_proxy]
+            local_addr = "127.0.0.1:50002"
+            remote_user = "app"
+            remote_ip = "10.0.0.2"
+            forward_ip = "39.0.0.208"
+            forward_port = 2222
+
+            [pac]
+            default = "staging"
+            "#,
+        )
+        .unwrap();
+        let res = sections(&mut config, None).unwrap();
+        assert_eq!(res.len(), 4);
+        let globals = &res[0].settings;
+        assert_eq!(globals["probe_check_interval"].value, Value::Integer(10));
+        assert_eq!(globals["probe_check_interval"].source, Source::File);
+        assert_eq!(globals["stop_timeout"].value, Value::Integer(5));
+        assert_eq!(globals["stop_timeout"].source, Source::Default);
+        assert!(!globals.contains_key("tunnels"));
+        assert_eq!(res[1].path, vec!["multi_proxy"]);
+        let multi = &res[1].settings;
+        assert_eq!(multi["remote_user"].value.as_str(), Some("app"));
+        assert_eq!(multi["remote_user"].source, Source::File);
+        assert_eq!(multi["remote_port"].value, Value::Integer(22));
+        assert_eq!(multi["remote_port"].source, Source::Default);
+        assert_eq!(multi["forward_user"].value.as_str(), Some("root"));
+        assert_eq!(multi["forward_user"].source, Source::Default);
+        assert_eq!(multi["forward_port"].value, Value::Integer(2222));
+        assert_eq!(multi["forward_port"].source, Source::File);
+        assert_eq!(res[2].path, vec!["tunnels", "staging"]);
+        let tunnel = &res[2].settings;
+        assert_eq!(tunnel["type"].value.as_str(), Some("dynamic"));
+        assert_eq!(tunnel["local_addr"].source, Source::File);
+        assert_eq!(tunnel["remote_user"].value.as_str(), Some("root"));
+        assert_eq!(tunnel["remote_user"].source, Source::Default);
+        assert_eq!(
+            tunnel["check_targets"].value,
+            Value::Array(vec![Value::String("www.baidu.com:443".to_string())])
+        );
+        assert!(tunnel["ssh_options"].value.is_table());
+        assert!(!tunnel.contains_key("http_listen"));
+        let pac = &res[3].settings;
+        assert_eq!(pac["listen"].value.as_str(), Some("localhost:50080"));
+        assert_eq!(pac["default"].source, Source::File);
+        let res = sections(&mut config, Some("staging")).unwrap();
+        assert_eq!(res.len(), 2);
+        assert!(sections(&mut config, Some("prod")).is_err());
+    }
+}
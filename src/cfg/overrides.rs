@@ -0,0 +1,354 @@
+use crate::cfg::effective::Source;
+use crate::cfg::{Config, DYNAMIC_PROXY, MULTI_PROXY};
+use crate::error::SshpError;
+use anyhow::Result;
+use toml::Value;
+
+/// prefix of the env vars overriding the config
+const ENV_PREFIX: &str = "SSHP_";
+
+/// top-level keys, keys of no section other than these are of the tunnel
+const GLOBAL_KEYS: [&str; 14] = [
+    "probe_check_interval",
+    "probe_failed_times_when_exit",
+    "stop_timeout",
+    "startup_timeout",
+    "probe_backoff_initial",
+    "probe_backoff_max",
+    "probe_backoff_multiplier",
+    "probe_backoff_jitter",
+    "probe_stable_period",
+    "probe_health_check_interval",
+    "probe_health_check_failures",
+    "probe_failback_interval",
+    "runtime_dir",
+    "known_hosts_file",
+];
+
+/// a value of the config set by an env var or `--set`
+#[derive(Debug, PartialEq)]
+pub struct Override {
+    /// path of the table the key is in, empty for the top level
+    pub section: Vec<String>,
+    /// dotted keys in the section
+    pub key: Vec<String>,
+    pub value: Value,
+    pub source: Source,
+    /// the env var or `--set` in errors
+    origin: String,
+}
+
+impl Override {
+    fn path(&self) -> Vec<String> {
+        self.section
+            .iter()
+            .chain(self.key.iter())
+            .cloned()
+            .collect()
+    }
+}
+
+/// a toml value, strings may be unquoted
+fn parse_value(s: &str) -> Value {
+    match toml::from_str::<Value>(format!("v = {}", s).as_str()) {
+        Ok(Value::Table(mut e)) => e
+            .remove("v")
+            .unwrap_or_else(|| Value::String(s.to_string())),
+        _ => Value::String(s.to_string()),
+    }
+}
+
+/// the section of the tunnel named `name`
+fn tunnel_section(name: &str) -> Vec<String> {
+    match name {
+        DYNAMIC_PROXY | MULTI_PROXY => vec![name.to_string()],
+        _ => vec!["tunnels".to_string(), name.to_string()],
+    }
+}
+
+/// the section and the key of `key`, keys without a section other than the top-level
+/// ones are of the tunnel named `tunnel`
+fn resolve(key: &str, tunnel: Option<&str>, origin: &str) -> Result<(Vec<String>, Vec<String>)> {
+    let mut keys: Vec<String> = key.split('.').map(|e| e.to_string()).collect();
+    if keys.iter().any(|e| e.is_empty()) {
+        return Err(
+            SshpError::ConfigInvalid(format!("Invalid key `{}` of {}", key, origin)).into(),
+        );
+    }
+    let len = match keys[0].as_str() {
+        DYNAMIC_PROXY | MULTI_PROXY => 1,
+        "tunnels" => 2,
+        e if GLOBAL_KEYS.contains(&e) => 0,
+        _ => match tunnel {
+            Some(e) => return Ok((tunnel_section(e), keys)),
+            None => {
+                return Err(SshpError::ConfigInvalid(format!(
+                    "{} needs a tunnel, set it as `<section>.{}`",
+                    origin, key
+                ))
+                .into())
+            }
+        },
+    };
+    if keys.len() <= len {
+        return Err(SshpError::ConfigInvalid(format!("{} sets no key", origin)).into());
+    }
+    let key = keys.split_off(len);
+    Ok((keys, key))
+}
+
+/// the section and the key of the env var named `SSHP_<name>` with `name` in lowercase,
+/// None if it does not name a key of a section of `config`
+fn resolve_env(config: &Value, name: &str) -> Option<(Vec<String>, String)> {
+    if GLOBAL_KEYS.contains(&name) {
+        return Some((Vec::new(), name.to_string()));
+    }
+    for section in [DYNAMIC_PROXY, MULTI_PROXY] {
+        if let Some(key) = name.strip_prefix(section).and_then(|e| e.strip_prefix('_')) {
+            if !key.is_empty() && config.get(section).is_some() {
+                return Some((vec![section.to_string()], key.to_string()));
+            }
+        }
+    }
+    // tunnel names with the chars not allowed in env var names as `_`, the longest
+    // matched one wins
+    let rest = name.strip_prefix("tunnels_")?;
+    config
+        .get("tunnels")?
+        .as_table()?
+        .keys()
+        .filter_map(|tunnel| {
+            let normalized: String = tunnel
+                .to_lowercase()
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            let key = rest.strip_prefix(normalized.as_str())?.strip_prefix('_')?;
+            if key.is_empty() {
+                return None;
+            }
+            Some((tunnel, key))
+        })
+        .max_by_key(|e| e.0.len())
+        .map(|(tunnel, key)| {
+            (
+                vec!["tunnels".to_string(), tunnel.to_string()],
+                key.to_string(),
+            )
+        })
+}
+
+/// `SSHP_<KEY>` for the top-level keys, `SSHP_DYNAMIC_PROXY_<KEY>`,
+/// `SSHP_MULTI_PROXY_<KEY>` and `SSHP_TUNNELS_<NAME>_<KEY>` for the keys of the sections
+/// in `config`, other `SSHP_*` env vars are ignored
+fn from_env(config: &Value) -> Vec<Override> {
+    let mut res = Vec::new();
+    for (name, value) in std::env::vars_os() {
+        let (name, value) = match (name.into_string(), value.into_string()) {
+            (Ok(name), Ok(value)) if name.starts_with(ENV_PREFIX) => (name, value),
+            _ => continue,
+        };
+        let (section, key) = match resolve_env(config, &name[ENV_PREFIX.len()..].to_lowercase()) {
+            Some(e) => e,
+            None => continue,
+        };
+        res.push(Override {
+            section,
+            key: vec![key],
+            value: parse_value(value.as_str()),
+            source: Source::Env,
+            origin: format!("env {}", name),
+        });
+    }
+    res.sort_by(|a, b| a.origin.cmp(&b.origin));
+    res
+}
+
+/// `key=value`s of `--set`, later ones win
+fn from_sets(sets: &[&str], tunnel: Option<&str>) -> Result<Vec<Override>> {
+    let mut res = Vec::new();
+    for e in sets {
+        let origin = format!("--set {}", e);
+        let (key, value) = match e.split_once('=') {
+            Some(e) => e,
+            None => {
+                return Err(SshpError::ConfigInvalid(format!(
+                    "Invalid {}, need `key=value`",
+                    origin
+                ))
+                .into())
+            }
+        };
+        let (section, key) = resolve(key.trim(), tunnel, origin.as_str())?;
+        res.push(Override {
+            section,
+            key,
+            value: parse_value(value.trim()),
+            source: Source::Cli,
+            origin,
+        });
+    }
+    Ok(res)
+}
+
+/// the overrides of the env vars, then of `sets`, on the value of the config file
+pub fn parse(config: &Value, sets: &[&str], tunnel: Option<&str>) -> Result<Vec<Override>> {
+    let mut res = from_env(config);
+    res.extend(from_sets(sets, tunnel)?);
+    Ok(res)
+}
+
+/// set the overrides into the value of the config file, their sections must be in it
+pub fn apply(config: &mut Value, overrides: &[Override]) -> Result<()> {
+    for e in overrides {
+        let mut table = match config.as_table_mut() {
+            Some(t) => t,
+            None => continue,
+        };
+        for (i, x) in e.section.iter().enumerate() {
+            table = match table.get_mut(x).and_then(|t| t.as_table_mut()) {
+                Some(t) => t,
+                None => {
+                    return Err(SshpError::ConfigInvalid(format!(
+                        "[{}] of {} is not in the config file",
+                        e.section[..=i].join("."),
+                        e.origin
+                    ))
+                    .into())
+                }
+            };
+        }
+        let (last, keys) = e.key.split_last().unwrap();
+        for x in keys {
+            let entry = table
+                .entry(x.to_string())
+                .or_insert_with(|| Value::Table(Default::default()));
+            table = match entry.as_table_mut() {
+                Some(t) => t,
+                None => {
+                    return Err(SshpError::ConfigInvalid(format!(
+                        "`{}` of {} is not a table",
+                        x, e.origin
+                    ))
+                    .into())
+                }
+            };
+        }
+        table.insert(last.to_string(), e.value.clone());
+    }
+    Ok(())
+}
+
+/// the overrides of keys known to the config, which drops the unknown ones when loading
+/// it, unknown keys of `--set` are errors
+pub fn check(config: &Config, overrides: Vec<Override>) -> Result<Vec<Override>> {
+    let value = Value::try_from(config)?;
+    let mut res = Vec::new();
+    for e in overrides {
+        let mut x = Some(&value);
+        for k in e.path() {
+            x = x.and_then(|t| t.get(k.as_str()));
+        }
+        match x {
+            Some(_) => res.push(e),
+            None if e.source == Source::Env => {}
+            None => {
+                return Err(SshpError::ConfigInvalid(format!(
+                    "Unknown config key `{}` of {}",
+                    e.key.join("."),
+                    e.origin
+                ))
+                .into())
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::effective::Source;
+    use crate::cfg::overrides::{apply, check, from_sets, parse_value, resolve_env};
+    use crate::cfg::Config;
+    use toml::Value;
+
+    #[test]
+    fn test_overrides() {
+        assert_eq!(parse_value("2222"), Value::Integer(2222));
+        assert_eq!(parse_value("true"), Value::Boolean(true));
+        assert_eq!(
+            parse_value("10.0.0.5"),
+            Value::String("10.0.0.5".to_string())
+        );
+        assert_eq!(
+            parse_value("[::1]:1080"),
+            Value::String("[::1]:1080".to_string())
+        );
+        assert_eq!(parse_value(r#""22""#), Value::String("22".to_string()));
+        let overrides = from_sets(
+            &[
+                "remote_ip=10.0.0.5",
+                "probe_check_interval=10",
+                "ssh_options.ConnectTimeout=5",
+                "multi_proxy.proxy_jump=true",
+            ],
+            Some("staging"),
+        )
+        .unwrap();
+        assert_eq!(overrides[0].section, vec!["tunnels", "staging"]);
+        assert_eq!(overrides[0].source, Source::Cli);
+        assert!(overrides[1].section.is_empty());
+        assert_eq!(overrides[2].key, vec!["ssh_options", "ConnectTimeout"]);
+        assert_eq!(overrides[3].section, vec!["multi_proxy"]);
+        assert!(from_sets(&["remote_ip"], Some("staging")).is_err());
+        assert!(from_sets(&["remote_ip=10.0.0.5"], None).is_err());
+        let mut value: Value = toml::from_str(
+            r#"
+            [tunnels.staging]
+            type = "dynamic"
+            local_addr = "localhost:50001"
+            remote_ip = "10.0.0.1"
+            "#,
+        )
+        .unwrap();
+        assert!(apply(&mut value.clone(), &overrides).is_err());
+        apply(&mut value, &overrides[..3]).unwrap();
+        let config: Config = value.try_into().unwrap();
+        let overrides = check(&config, overrides.into_iter().take(3).collect()).unwrap();
+        assert_eq!(overrides.len(), 3);
+        let overrides = from_sets(&["remote_ipp=10.0.0.5"], Some("staging")).unwrap();
+        let mut value = Value::try_from(&config).unwrap();
+        apply(&mut value, &overrides).unwrap();
+        let config: Config = value.try_into().unwrap();
+        assert!(check(&config, overrides).is_err());
+        let value: Value = toml::from_str(
+            r#"
+            [tunnels.staging]
+            [tunnels.my-db]
+            [tunnels.my]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            resolve_env(&value, "probe_check_interval"),
+            Some((Vec::new(), "probe_check_interval".to_string()))
+        );
+        assert_eq!(
+            resolve_env(&value, "tunnels_staging_remote_ip"),
+            Some((
+                vec!["tunnels".to_string(), "staging".to_string()],
+                "remote_ip".to_string()
+            ))
+        );
+        assert_eq!(
+            resolve_env(&value, "tunnels_my_db_remote_port"),
+            Some((
+                vec!["tunnels".to_string(), "my-db".to_string()],
+                "remote_port".to_string()
+            ))
+        );
+        assert_eq!(resolve_env(&value, "dynamic_proxy_remote_ip"), None);
+        assert_eq!(resolve_env(&value, "debug"), None);
+        assert_eq!(resolve_env(&value, "tunnels_prod_remote_ip"), None);
+    }
+}
@@ -0,0 +1,524 @@
+use crate::cfg::{
+    Addr, CheckConfig, CheckTarget, Config, DynamicProxyConfig, Forward, ForwardConfig,
+    MultiDynamicProxyConfig, SshConfig, TunnelConfig, DYNAMIC_PROXY, MULTI_PROXY,
+};
+use crate::error::SshpError;
+use anyhow::Result;
+
+/// problems of the config, each naming the key and its line in the source
+struct Validator<'a> {
+    source: &'a str,
+    errors: Vec<String>,
+}
+
+/// a tunnel, including the `dynamic_proxy` and `multi_proxy` sections
+enum TunnelRef<'a> {
+    Dynamic(&'a DynamicProxyConfig),
+    Multi(&'a MultiDynamicProxyConfig),
+    Local(&'a ForwardConfig),
+    Remote(&'a ForwardConfig),
+}
+
+/// a local addr some process of the config listens on, configured by `key` of `table`
+struct Listen {
+    table: String,
+    key: &'static str,
+    host: String,
+    port: u16,
+}
+
+impl Listen {
+    fn name(&self) -> String {
+        format!("{}.{}", self.table, self.key)
+    }
+}
+
+impl<'a> Validator<'a> {
+    /// record msg about `key` of `table`, the table itself if key is None
+    fn error(&mut self, table: &str, key: Option<&str>, msg: String) {
+        let path = match key {
+            Some(e) if table.is_empty() => e.to_string(),
+            Some(e) => format!("{}.{}", table, e),
+            None => table.to_string(),
+        };
+        match find_line(self.source, table, key) {
+            Some(line) => self
+                .errors
+                .push(format!("`{}` at line {}, {}", path, line, msg)),
+            None => self.errors.push(format!("`{}`, {}", path, msg)),
+        }
+    }
+
+    fn check_positive(&mut self, table: &str, key: &str, value: Option<f64>) {
+        if let Some(e) = value {
+            if e <= 0.0 {
+                self.error(table, Some(key), format!("{} must be greater than 0", e));
+            }
+        }
+    }
+
+    fn check_port(&mut self, table: &str, key: &str, value: Option<usize>) {
+        if let Some(e) = value {
+            if !(1..=65535).contains(&e) {
+                self.error(table, Some(key), format!("port {} out of range 1-65535", e));
+            }
+        }
+    }
+
+    fn check_addr(
+        &mut self,
+        listens: &mut Vec<Listen>,
+        table: &str,
+        key: &'static str,
+        addr: &Addr,
+    ) {
+        if addr.port == 0 {
+            self.error(
+                table,
+                Some(key),
+                format!("port of {} must not be 0", addr.as_str()),
+            );
+        }
+        listens.push(Listen {
+            table: table.to_string(),
+            key,
+            host: addr.host.as_str().to_string(),
+            port: addr.port,
+        });
+    }
+
+    fn check_check(&mut self, table: &str, config: &CheckConfig) {
+        if let Some(ref e) = config.check {
+            if e != "none" {
+                self.error(table, Some("check"), format!("`{}` is not `none`", e));
+            }
+        }
+        let targets = [
+            ("check_url", &config.check_url),
+            ("check_host", &config.check_host),
+        ];
+        for (key, value) in targets {
+            if let Some(Err(e)) = value.as_ref().map(|e| e.parse::<CheckTarget>()) {
+                self.error(table, Some(key), e.to_string());
+            }
+        }
+        for e in config.check_targets.iter().flatten() {
+            if let Err(e) = e.parse::<CheckTarget>() {
+                self.error(table, Some("check_targets"), e.to_string());
+            }
+        }
+        if let Some(ref e) = config.check_mode {
+            if e != "any" && e != "all" {
+                self.error(
+                    table,
+                    Some("check_mode"),
+                    format!("`{}` is not `any` or `all`", e),
+                );
+            }
+        }
+    }
+
+    fn check_ssh(&mut self, table: &str, config: &SshConfig) {
+        if let Some(ref e) = config.host_key_policy {
+            if !["strict", "accept-new", "off"].contains(&e.as_str()) {
+                self.error(
+                    table,
+                    Some("host_key_policy"),
+                    format!("`{}` is not `strict`, `accept-new` or `off`", e),
+                );
+            }
+        }
+        if let Some(ref e) = config.host_key_fingerprint {
+            if !e.starts_with("SHA256:") {
+                self.error(
+                    table,
+                    Some("host_key_fingerprint"),
+                    format!("`{}` is not a `SHA256:...` fingerprint", e),
+                );
+            }
+        }
+    }
+}
+
+/// check the config beyond the toml syntax: addrs, ports, intervals, references between
+/// the sections and local addrs listened on twice
+pub fn validate(config: &mut Config, source: &str) -> Result<()> {
+    let mut v = Validator {
+        source,
+        errors: Vec::new(),
+    };
+    let globals = [
+        (
+            "probe_check_interval",
+            config.probe_check_interval.map(f64::from),
+        ),
+        (
+            "probe_failed_times_when_exit",
+            config.probe_failed_times_when_exit.map(f64::from),
+        ),
+        ("stop_timeout", config.stop_timeout.map(|e| e as f64)),
+        ("startup_timeout", config.startup_timeout.map(|e| e as f64)),
+        (
+            "probe_backoff_initial",
+            config.probe_backoff_initial.map(|e| e as f64),
+        ),
+        (
+            "probe_backoff_max",
+            config.probe_backoff_max.map(|e| e as f64),
+        ),
+        (
+            "probe_stable_period",
+            config.probe_stable_period.map(|e| e as f64),
+        ),
+        (
+            "probe_health_check_failures",
+            config.probe_health_check_failures.map(f64::from),
+        ),
+        (
+            "probe_failback_interval",
+            config.probe_failback_interval.map(|e| e as f64),
+        ),
+    ];
+    for (key, value) in globals {
+        v.check_positive("", key, value);
+    }
+    if let Some(e) = config.probe_backoff_multiplier {
+        if e < 1.0 {
+            v.error(
+                "",
+                Some("probe_backoff_multiplier"),
+                format!("{} is less than 1", e),
+            );
+        }
+    }
+    if let Some(e) = config.probe_backoff_jitter {
+        if !(0.0..=1.0).contains(&e) {
+            v.error(
+                "",
+                Some("probe_backoff_jitter"),
+                format!("{} is not between 0 and 1", e),
+            );
+        }
+    }
+    if let (Some(initial), Some(max)) = (config.probe_backoff_initial, config.probe_backoff_max) {
+        if max < initial {
+            v.error(
+                "",
+                Some("probe_backoff_max"),
+                format!("{} is less than probe_backoff_initial {}", max, initial),
+            );
+        }
+    }
+    let mut listens = Vec::new();
+    for name in config.get_tunnel_names() {
+        let table = match name.as_str() {
+            DYNAMIC_PROXY | MULTI_PROXY => name.clone(),
+            _ => format!("tunnels.{}", name),
+        };
+        config.tunnel = Some(name.clone());
+        let tunnel = match name.as_str() {
+            DYNAMIC_PROXY => config.dynamic_proxy.as_ref().map(TunnelRef::Dynamic),
+            MULTI_PROXY => config.multi_proxy.as_ref().map(TunnelRef::Multi),
+            _ => match config.get_tunnel(name.as_str()) {
+                Some(TunnelConfig::Dynamic(e)) => Some(TunnelRef::Dynamic(e)),
+                Some(TunnelConfig::Multi(e)) => Some(TunnelRef::Multi(e)),
+                Some(TunnelConfig::Local(e)) => Some(TunnelRef::Local(e)),
+                Some(TunnelConfig::Remote(e)) => Some(TunnelRef::Remote(e)),
+                None => None,
+            },
+        };
+        match tunnel {
+            Some(TunnelRef::Dynamic(e)) => {
+                let t = table.as_str();
+                v.check_addr(&mut listens, t, "local_addr", &e.local_addr);
+                if let Some(ref addr) = e.http_listen {
+                    v.check_addr(&mut listens, t, "http_listen", addr);
+                }
+                v.check_port(t, "remote_port", e.remote_port);
+                v.check_positive(
+                    t,
+                    "heart_beat_interval",
+                    e.heart_beat_interval.map(|e| e as f64),
+                );
+                let has_hosts = e
+                    .remote_hosts
+                    .as_ref()
+                    .map(|e| !e.is_empty())
+                    .unwrap_or(false);
+                if e.remote_ip.is_none() && !has_hosts && e.ssh.ssh_config_host.is_none() {
+                    v.error(
+                        t,
+                        None,
+                        "needs `remote_ip`, `remote_hosts` or `ssh_config_host`".to_string(),
+                    );
+                }
+                if has_hosts {
+                    match config.get_dynamic_remote_hosts() {
+                        Ok(hosts) => {
+                            for host in hosts {
+                                v.check_port(t, "remote_hosts", Some(host.port));
+                            }
+                        }
+                        Err(err) => v.error(t, Some("remote_hosts"), err.to_string()),
+                    }
+                }
+                v.check_check(t, &e.check);
+                v.check_ssh(t, &e.ssh);
+            }
+            Some(TunnelRef::Multi(e)) => {
+                let t = table.as_str();
+                v.check_addr(&mut listens, t, "local_addr", &e.local_addr);
+                if let Some(ref addr) = e.http_listen {
+                    v.check_addr(&mut listens, t, "http_listen", addr);
+                }
+                v.check_port(t, "remote_port", e.remote_port);
+                v.check_port(t, "forward_port", e.forward_port);
+                v.check_port(t, "local_forward_port", e.local_forward_port);
+                v.check_positive(
+                    t,
+                    "heart_beat_interval",
+                    e.heart_beat_interval.map(|e| e as f64),
+                );
+                match e.hops {
+                    Some(ref hops) if hops.len() < 2 => {
+                        v.error(t, Some("hops"), "needs at least 2 hosts".to_string())
+                    }
+                    Some(ref hops) => {
+                        for hop in hops.iter() {
+                            v.check_port(t, "hops", hop.port);
+                        }
+                    }
+                    None if e.forward_ip.is_none() || e.remote_ip.is_none() => v.error(
+                        t,
+                        None,
+                        "needs `hops` or both `forward_ip` and `remote_ip`".to_string(),
+                    ),
+                    None => {}
+                }
+                // the first forward of the chain listens on the loopback
+                if let Some(port) = e.local_forward_port.filter(|e| *e <= 65535) {
+                    listens.push(Listen {
+                        table: t.to_string(),
+                        key: "local_forward_port",
+                        host: e.local_addr.get_loopback().to_string(),
+                        port: port as u16,
+                    });
+                }
+                v.check_check(t, &e.check);
+                v.check_ssh(t, &e.ssh);
+            }
+            Some(TunnelRef::Local(e)) | Some(TunnelRef::Remote(e)) => {
+                let t = table.as_str();
+                let local = matches!(tunnel, Some(TunnelRef::Local(_)));
+                if e.forwards.is_empty() {
+                    v.error(t, Some("forwards"), "needs at least 1 forward".to_string());
+                }
+                for spec in e.forwards.iter() {
+                    match spec.parse::<Forward>() {
+                        Ok(forward) if forward.port == 0 || forward.host_port == 0 => v.error(
+                            t,
+                            Some("forwards"),
+                            format!("port of `{}` must not be 0", spec),
+                        ),
+                        Ok(forward) if local => listens.push(Listen {
+                            table: t.to_string(),
+                            key: "forwards",
+                            host: forward.bind.clone().unwrap_or_default(),
+                            port: forward.port,
+                        }),
+                        Ok(_) => {}
+                        Err(err) => v.error(t, Some("forwards"), err.to_string()),
+                    }
+                }
+                v.check_port(t, "remote_port", e.remote_port);
+                v.check_positive(
+                    t,
+                    "heart_beat_interval",
+                    e.heart_beat_interval.map(|e| e as f64),
+                );
+                if e.remote_ip.is_none() && e.ssh.ssh_config_host.is_none() {
+                    v.error(
+                        t,
+                        None,
+                        "needs `remote_ip` or `ssh_config_host`".to_string(),
+                    );
+                }
+                v.check_check(t, &e.check);
+                // forwards are checked by themselves, there are no targets to connect
+                let keys = [
+                    ("check_url", e.check.check_url.is_some()),
+                    ("check_host", e.check.check_host.is_some()),
+                    ("check_targets", e.check.check_targets.is_some()),
+                    ("check_mode", e.check.check_mode.is_some()),
+                ];
+                for (key, _) in keys.iter().filter(|e| e.1) {
+                    v.error(
+                        t,
+                        Some(key),
+                        "is not supported by forward tunnels, only `check = \"none\"` is"
+                            .to_string(),
+                    );
+                }
+                v.check_ssh(t, &e.ssh);
+            }
+            None => {}
+        }
+    }
+    config.tunnel = None;
+    if let Some(ref pac) = config.pac {
+        if let Some(ref addr) = pac.listen {
+            v.check_addr(&mut listens, "pac", "listen", addr);
+        }
+        if let Err(e) = config.get_pac_default() {
+            v.error("pac", Some("default"), e.to_string());
+        }
+        if let Err(e) = config.get_pac_rules() {
+            v.error("pac", Some("rules"), e.to_string());
+        }
+    }
+    if let Some(ref router) = config.router {
+        if let Some(ref addr) = router.listen {
+            v.check_addr(&mut listens, "router", "listen", addr);
+        }
+        if let Err(e) = config.get_router_default() {
+            v.error("router", Some("default"), e.to_string());
+        }
+        if let Err(e) = config.get_router_rules() {
+            v.error("router", Some("rules"), e.to_string());
+        }
+    }
+    for (i, a) in listens.iter().enumerate() {
+        if let Some(b) = listens[..i].iter().find(|b| collides(a, b)) {
+            let other = match find_line(source, b.table.as_str(), Some(b.key)) {
+                Some(line) => format!("`{}` at line {}", b.name(), line),
+                None => format!("`{}`", b.name()),
+            };
+            v.error(
+                a.table.as_str(),
+                Some(a.key),
+                format!("port {} is also listened on by {}", a.port, other),
+            );
+        }
+    }
+    if v.errors.is_empty() {
+        return Ok(());
+    }
+    Err(SshpError::ConfigInvalid(format!("Config is invalid:\n  {}", v.errors.join("\n  "))).into())
+}
+
+/// whether two listens may bind the same addr, loopbacks are the same host and a
+/// wildcard host binds all
+fn collides(a: &Listen, b: &Listen) -> bool {
+    let normalize = |e: &str| match e.to_ascii_lowercase().as_str() {
+        "localhost" | "127.0.0.1" | "::1" => "loopback".to_string(),
+        "" | "*" | "0.0.0.0" | "::" => "*".to_string(),
+        e => e.to_string(),
+    };
+    let (x, y) = (normalize(a.host.as_str()), normalize(b.host.as_str()));
+    a.port == b.port && (x == y || x == "*" || y == "*")
+}
+
+/// 1-based line of `key` in `table` of the toml source, or of the table header if key is
+/// None or not found, `table` is `a.b` for `[a.b]` and empty for the top level
+pub fn find_line(source: &str, table: &str, key: Option<&str>) -> Option<usize> {
+    let mut current = String::new();
+    let mut header = if table.is_empty() { Some(0) } else { None };
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            current = line
+                .trim_start_matches('[')
+                .split(']')
+                .next()
+                .unwrap_or_default()
+                .split('.')
+                .map(|e| e.trim().trim_matches('"'))
+                .collect::<Vec<&str>>()
+                .join(".");
+            if current == table {
+                header = Some(i + 1);
+            }
+            continue;
+        }
+        if current != table {
+            continue;
+        }
+        if let (Some(key), Some((k, _))) = (key, line.split_once('=')) {
+            if k.trim().trim_matches('"') == key {
+                return Some(i + 1);
+            }
+        }
+    }
+    header.filter(|e| *e > 0)
+}
+
+/// the toml error with the line of the key it names, as the errors of the values
+/// parsed by serde point at the start of their table
+pub fn describe_toml_error(source: &str, e: &toml::de::Error) -> String {
+    let msg = e.to_string();
+    let (head, key) = match msg.split_once(" for key `") {
+        Some((head, rest)) => (head, rest.split('`').next().unwrap_or_default()),
+        None => return msg,
+    };
+    let (table, name) = key.rsplit_once('.').unwrap_or(("", key));
+    match find_line(source, table, Some(name)) {
+        Some(line) => format!("`{}` at line {}, {}", key, line, head),
+        None => msg,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::validate::{find_line, validate};
+    use crate::cfg::Config;
+
+    #[test]
+    fn test_validate() {
+        let source = r#"
+probe_check_interval = 0
+
+[dynamic_proxy]
+local_addr = "localhost:50001"
+remote_ip = "10.0.0.1"
+
+[tunnels."staging"]
+type = "dynamic"
+local_addr = "127.0.0.1:50001"
+remote_ip = "10.0.0.2"
+remote_port = 70000
+
+[tunnels.db]
+type = "local"
+remote_ip = "10.0.0.3"
+forwards = ["5432:db.internal:5432"]
+check_host = "10.0.0.1:22"
+"#;
+        assert_eq!(find_line(source, "", Some("probe_check_interval")), Some(2));
+        assert_eq!(
+            find_line(source, "tunnels.staging", Some("remote_port")),
+            Some(12)
+        );
+        assert_eq!(
+            find_line(source, "tunnels.staging", Some("check_host")),
+            Some(8)
+        );
+        let mut config: Config = toml::from_str(source).unwrap();
+        let err = validate(&mut config, source).unwrap_err().to_string();
+        assert!(err.contains("`probe_check_interval` at line 2, 0 must be greater than 0"));
+        assert!(err.contains("`tunnels.staging.remote_port` at line 12, port 70000 out of range"));
+        assert!(err.contains(
+            "`tunnels.staging.local_addr` at line 10, port 50001 is also listened on by \
+             `dynamic_proxy.local_addr` at line 5"
+        ));
+        assert!(
+            err.contains("`tunnels.db.check_host` at line 18, is not supported by forward tunnels")
+        );
+        let source = source
+            .replace("probe_check_interval = 0", "probe_check_interval = 5")
+            .replace("127.0.0.1:50001", "127.0.0.1:50011")
+            .replace("70000", "22")
+            .replace(r#"check_host = "10.0.0.1:22""#, r#"check = "none""#);
+        let mut config: Config = toml::from_str(source.as_str()).unwrap();
+        assert!(validate(&mut config, source.as_str()).is_ok());
+    }
+}
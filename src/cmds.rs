@@ -1,74 +1,614 @@
+pub mod config;
 pub mod dynamic_proxy;
+pub mod forward;
 pub mod multi_proxy;
-use crate::cfg::Config;
+pub mod pac;
+pub mod router;
+pub mod status;
+pub mod trust;
+use crate::backoff::Backoff;
+use crate::cfg::{Config, Hop};
+use crate::error::{self, SshpError};
+use crate::http_proxy;
+use crate::known_hosts;
+use crate::state::{Process, State};
 use crate::utils;
 use anyhow::Result;
 use clap::{ArgMatches, Command};
 use fork::{fork, Fork};
-use std::io::Write;
+use std::io::prelude::*;
 
 pub trait SubCmd {
     fn usage<'a>() -> Command<'a>;
     fn handler(&self, arg: &ArgMatches) -> Result<()>;
 }
 
+/// one ssh process of a tunnel
+pub struct SshCommand {
+    /// args without `-f`
+    pub args: Vec<String>,
+    /// the local addrs the ssh listens on once it is ready, empty for remote forwards
+    pub listens: Vec<String>,
+}
+
 pub trait Start {
-    fn start(&self, config: &Config, echo: bool) -> Result<()>;
+    /// listen addr of the tunnel
+    fn get_addr<'a>(&self, config: &'a Config) -> &'a str;
+    /// ssh processes of the tunnel in start order, connecting the `upstream`th remote host
+    /// of `get_upstreams`
+    fn commands(&self, config: &Config, upstream: usize) -> Result<Vec<SshCommand>>;
+
+    /// remote hosts to fall through in order of preference, empty if there is no choice
+    fn get_upstreams(&self, _config: &Config) -> Result<Vec<Hop>> {
+        Ok(Vec::new())
+    }
+
+    /// start with the preferred upstream, fall through to the next one if it fails
+    fn start(&self, config: &Config, echo: bool) -> Result<()> {
+        let addr = self.get_addr(config);
+        let log_path = utils::get_log_file(config, addr);
+        let upstreams = self.get_upstreams(config)?;
+        let mut upstream = 0;
+        loop {
+            match self.start_upstream(config, upstream, echo) {
+                Ok(_) => break,
+                Err(e) if upstream + 1 < upstreams.len() => {
+                    let msg = format!(
+                        "{} upstream {} failed, {}, fall through to {}",
+                        addr,
+                        upstreams[upstream].name(),
+                        e,
+                        upstreams[upstream + 1].name()
+                    );
+                    utils::write_log(&log_path, msg.as_str());
+                    if echo {
+                        utils::print_with_color(format!("{}\n", msg).as_str(), 33, false);
+                    }
+                    upstream += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if upstreams.len() > 1 {
+            let name = upstreams[upstream].name();
+            let msg = format!("{} active upstream is {}", addr, name);
+            utils::write_log(&log_path, msg.as_str());
+            if echo {
+                utils::print_with_color(format!("{}\n", msg).as_str(), 37, false);
+            }
+            let mut state = State::load(config, addr);
+            state.upstream = Some(name);
+            state.save(config, addr)?;
+        }
+        Ok(())
+    }
+
+    fn start_upstream(&self, config: &Config, upstream: usize, echo: bool) -> Result<()> {
+        let addr = self.get_addr(config);
+        known_hosts::prepare(config, upstream)?;
+        let mut state = State::empty(config, addr);
+        for command in self.commands(config, upstream)? {
+            self.run_ssh(config, command, &mut state, echo)?;
+        }
+        if !utils::check_result(config, utils::check(config, addr), addr, echo) {
+            self.stop(config, echo)?;
+            return Err(SshpError::HealthCheckFailed(format!("check {} failed.", addr)).into());
+        }
+        Ok(())
+    }
+
+    /// whether to restart the tunnel to return to the preferred upstream, which is tried
+    /// every `probe_failback_interval` since `checked`
+    fn should_failback(&self, config: &Config, checked: &mut std::time::Instant) -> bool {
+        if checked.elapsed() < config.get_probe_failback_interval() {
+            return false;
+        }
+        *checked = std::time::Instant::now();
+        let addr = self.get_addr(config);
+        let preferred = match self.get_upstreams(config) {
+            Ok(e) if e.len() > 1 => e[0].clone(),
+            _ => return false,
+        };
+        if State::load(config, addr).upstream == Some(preferred.name())
+            || !utils::is_reachable(preferred.ip.as_str(), preferred.port)
+        {
+            return false;
+        }
+        utils::write_log(
+            &utils::get_log_file(config, addr),
+            format!(
+                "{} preferred upstream {} is reachable, restart to return to it",
+                addr,
+                preferred.name()
+            )
+            .as_str(),
+        );
+        true
+    }
+
+    /// run one ssh in background in a new session like `ssh -f`, record it in the state,
+    /// stop the tunnel if it fails
+    fn run_ssh(
+        &self,
+        config: &Config,
+        command: SshCommand,
+        state: &mut State,
+        echo: bool,
+    ) -> Result<()> {
+        let addr = self.get_addr(config);
+        let log_path = utils::get_log_file(config, addr);
+        let offset = std::fs::metadata(&log_path).map(|e| e.len()).unwrap_or(0);
+        let log_file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        if let Err(e) = utils::ensure_not_listening(&command.listens) {
+            self.stop(config, echo)?;
+            return Err(SshpError::AddressInUse(format!(
+                "Open {} failed, {}",
+                utils::get_tunnel_kind(config).to_lowercase(),
+                e
+            ))
+            .into());
+        }
+        // ssh outlives sshp, so its stderr goes to the log file instead of a pipe
+        let mut ssh = std::process::Command::new("ssh");
+        ssh.args(command.args)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(log_file);
+        #[cfg(target_family = "unix")]
+        unsafe {
+            use std::os::unix::process::CommandExt;
+            ssh.pre_exec(|| {
+                libc::setsid();
+                Ok(())
+            });
+        }
+        let mut child = ssh.spawn()?;
+        let read_stderr = || {
+            let mut stderr = String::new();
+            if let Ok(mut f) = std::fs::File::open(&log_path) {
+                if f.seek(std::io::SeekFrom::Start(offset)).is_ok() {
+                    f.read_to_string(&mut stderr).ok();
+                }
+            }
+            stderr
+        };
+        if let Err(e) = utils::wait_for_listen(
+            &command.listens,
+            &mut child,
+            config.get_startup_timeout(),
+            read_stderr,
+        ) {
+            child.kill().ok();
+            child.wait().ok();
+            self.stop(config, echo)?;
+            let stderr = read_stderr();
+            return Err(error::classify_ssh_error(
+                stderr.as_str(),
+                format!(
+                    "Open {} failed, {}:\n{}",
+                    utils::get_tunnel_kind(config).to_lowercase(),
+                    e,
+                    stderr.trim()
+                ),
+            ));
+        }
+        // record after exec, the cmdline is still sshp's right after fork
+        state.processes.push(Process::new(child.id() as usize)?);
+        state.save(config, addr)?;
+        Ok(())
+    }
+
+    /// stop the ssh processes recorded in the state
+    fn stop(&self, config: &Config, echo: bool) -> Result<()> {
+        let addr = self.get_addr(config);
+        let log_path = utils::get_log_file(config, addr);
+        let pids = State::load(config, addr).get_alive_pids();
+        for pid in pids.as_slice() {
+            #[cfg(target_family = "unix")]
+            if let Some(signal) = utils::kill_child_by_pid(*pid, config.get_stop_timeout())? {
+                let msg = format!(
+                    "{} ssh {} stopped by {}",
+                    addr,
+                    pid,
+                    utils::get_signal_name(signal)
+                );
+                utils::write_log(&log_path, msg.as_str());
+                if echo {
+                    utils::print_with_color(format!("{}\n", msg).as_str(), 37, false);
+                }
+            }
+            #[cfg(target_os = "windows")]
+            utils::kill_child_by_pid_windows(pid)?;
+        }
+        State::empty(config, addr).save(config, addr)?;
+        if echo {
+            if !pids.is_empty() {
+                utils::print_with_color("Stop Success!\n", 34, false);
+            } else {
+                utils::print_with_color("No Process to Kill.\n", 33, false);
+            }
+        }
+        Ok(())
+    }
+
     fn start_with_probe(&self, config: &Config, addr: &str) -> Result<()> {
-        let log_path = utils::get_log_file(addr);
+        let log_path = utils::get_log_file(config, addr);
+        // bind before fork to report errors, the probe serves it
+        let http_listener = bind_http_listener(config)?;
         match fork() {
             Ok(Fork::Parent(child)) => {
-                let pid_file_path = utils::get_pid_file(addr);
-                let mut pid_file = std::fs::File::create(pid_file_path)?;
-                pid_file.write_all(child.to_string().as_bytes())?;
+                drop(http_listener);
+                // the probe does not exec, its cmdline is sshp's
+                State::save_probe(config, addr, Some(Process::new(child as usize)?))?;
                 utils::write_log(&log_path, format!("{} start ...", addr).as_str());
                 self.start(config, true)?;
+                if let Some(e) = config.get_http_listen() {
+                    utils::print_with_color("HTTP proxy listen addr is ", 32, false);
+                    utils::print_with_color(e, 37, true);
+                    println!();
+                }
             }
             Ok(Fork::Child) => {
+                serve_http_proxy(http_listener, addr, &log_path);
                 std::thread::sleep(std::time::Duration::from_secs(30));
                 let mut failed_times = 0;
+                let mut backoff = Backoff::new(config);
+                let mut started = std::time::Instant::now();
+                let mut checked = std::time::Instant::now();
+                let mut check_failed_times = 0;
+                let mut failback_checked = std::time::Instant::now();
                 loop {
-                    if let Ok(pids) = utils::get_pids(addr) {
-                        if pids.is_empty() {
+                    if State::load(config, addr).is_alive() {
+                        let interval = config.get_probe_health_check_interval();
+                        // a hung ssh keeps its pid, check through the tunnel
+                        let healthy = if interval.as_secs() > 0 && checked.elapsed() >= interval {
+                            checked = std::time::Instant::now();
+                            utils::health_check(config, addr, &mut check_failed_times)
+                        } else {
+                            true
+                        } && !self.should_failback(config, &mut failback_checked);
+                        if healthy {
+                            if check_failed_times == 0
+                                && started.elapsed() >= config.get_probe_stable_period()
+                            {
+                                backoff.reset();
+                            }
+                            std::thread::sleep(std::time::Duration::from_secs(
+                                config.get_probe_check_interval() as u64,
+                            ));
+                            continue;
+                        }
+                    }
+                    // kill the rest of a broken chain
+                    if let Err(e) = self.stop(config, false) {
+                        utils::write_log(&log_path, format!("{} stop error, {}", addr, e).as_str());
+                    }
+                    let delay = backoff.next_delay();
+                    if delay.as_secs() > 0 {
+                        let next_restart = (chrono::Local::now()
+                            + chrono::Duration::from_std(delay)?)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string();
+                        utils::write_log(
+                            &log_path,
+                            format!(
+                                "{} next restart at {}, in {}s",
+                                addr,
+                                next_restart,
+                                delay.as_secs()
+                            )
+                            .as_str(),
+                        );
+                        let mut state = State::load(config, addr);
+                        state.next_restart = Some(next_restart);
+                        state.save(config, addr)?;
+                        std::thread::sleep(delay);
+                    }
+                    utils::write_log(&log_path, format!("{} start in probe ...", addr).as_str());
+                    started = std::time::Instant::now();
+                    if let Err(e) = self.start(config, false) {
+                        failed_times += 1;
+                        utils::write_log(
+                            &log_path,
+                            format!("{} restart {}th error happend, {}", addr, failed_times, e)
+                                .as_str(),
+                        );
+                        if failed_times >= config.get_probe_failed_times_when_exit() {
                             utils::write_log(
                                 &log_path,
-                                format!("{} start in probe ...", addr).as_str(),
+                                format!(
+                                    "{} failed {} times, probe process will exit.",
+                                    addr, failed_times
+                                )
+                                .as_str(),
                             );
-                            if let Err(e) = self.start(config, false) {
-                                failed_times += 1;
-                                utils::write_log(
-                                    &log_path,
-                                    format!(
-                                        "{} restart {}th error happend, {}",
-                                        addr, failed_times, e
-                                    )
-                                    .as_str(),
-                                );
-                                if failed_times >= config.get_probe_failed_times_when_exit() {
-                                    utils::write_log(
-                                        &log_path,
-                                        format!(
-                                            "{} failed {} times, probe process will exit.",
-                                            addr, failed_times
-                                        )
-                                        .as_str(),
-                                    );
-                                    std::process::exit(1);
+                            std::process::exit(1);
+                        }
+                    } else {
+                        failed_times = 0;
+                    }
+                }
+            }
+            Err(e) => {
+                return Err(SshpError::ForkFailed(format!("Fork failed, {}", e)).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// stay in the foreground as the supervisor of ssh, restart ssh when it exits and
+    /// forward SIGTERM and SIGINT to it
+    fn start_foreground(&self, config: &Config, addr: &str) -> Result<()> {
+        let log_path = utils::get_log_file(config, addr);
+        let log = |msg: String| {
+            println!("{}", msg);
+            utils::write_log(&log_path, msg.as_str());
+        };
+        State::save_probe(
+            config,
+            addr,
+            Some(Process::new(std::process::id() as usize)?),
+        )?;
+        utils::handle_terminate_signals();
+        serve_http_proxy(bind_http_listener(config)?, addr, &log_path);
+        let mut failed_times = 0;
+        let mut exit_code = error::EXIT_FAILED;
+        let mut backoff = Backoff::new(config);
+        let upstreams = self.get_upstreams(config)?;
+        let mut upstream = 0;
+        while utils::get_terminate_signal().is_none() {
+            log(format!("{} start in foreground ...", addr));
+            let mut children = Vec::new();
+            let mut state = State::empty(config, addr);
+            let mut res = known_hosts::prepare(config, upstream);
+            if res.is_ok() {
+                match self.commands(config, upstream) {
+                    Ok(commands) => {
+                        for command in commands {
+                            res = utils::ensure_not_listening(&command.listens);
+                            if res.is_err() {
+                                break;
+                            }
+                            match std::process::Command::new("ssh")
+                                .args(command.args)
+                                .stdin(std::process::Stdio::null())
+                                .stderr(std::process::Stdio::piped())
+                                .spawn()
+                            {
+                                Ok(mut child) => {
+                                    let stderr = utils::stream_stderr(&mut child, &log_path);
+                                    if let Err(e) = utils::wait_for_listen(
+                                        &command.listens,
+                                        &mut child,
+                                        config.get_startup_timeout(),
+                                        || stderr.lock().map(|e| e.clone()).unwrap_or_default(),
+                                    ) {
+                                        child.kill().ok();
+                                        child.wait().ok();
+                                        let stderr = utils::read_streamed_stderr(&stderr);
+                                        res = Err(error::classify_ssh_error(
+                                            stderr.as_str(),
+                                            format!(
+                                                "Open {} failed, {}:\n{}",
+                                                utils::get_tunnel_kind(config).to_lowercase(),
+                                                e,
+                                                stderr.trim()
+                                            ),
+                                        ));
+                                    } else {
+                                        match Process::new(child.id() as usize) {
+                                            Ok(e) => state.processes.push(e),
+                                            Err(e) => res = Err(e),
+                                        }
+                                    }
+                                    children.push(child);
                                 }
-                            } else {
-                                failed_times = 0;
+                                Err(e) => res = Err(e.into()),
+                            }
+                            if res.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => res = Err(e),
+                }
+            }
+            if res.is_ok() && upstreams.len() > 1 {
+                let name = upstreams[upstream].name();
+                log(format!("{} active upstream is {}", addr, name));
+                state.upstream = Some(name);
+            }
+            if let Err(e) = state.save(config, addr) {
+                log(format!("{} save state error, {}", addr, e));
+            }
+            if res.is_ok() && !utils::check_result(config, utils::check(config, addr), addr, true) {
+                res = Err(SshpError::HealthCheckFailed(format!("check {} failed.", addr)).into());
+            }
+            match res {
+                Ok(_) => {
+                    failed_times = 0;
+                    let started = std::time::Instant::now();
+                    let mut checked = std::time::Instant::now();
+                    let mut check_failed_times = 0;
+                    let mut failback_checked = std::time::Instant::now();
+                    // wait until any ssh exits, the health check fails, the preferred
+                    // upstream is back or a signal comes
+                    'wait: while utils::get_terminate_signal().is_none() {
+                        for child in children.iter_mut() {
+                            if let Ok(Some(status)) = child.try_wait() {
+                                log(format!("{} ssh {} exited, {}", addr, child.id(), status));
+                                break 'wait;
+                            }
+                        }
+                        let interval = config.get_probe_health_check_interval();
+                        if interval.as_secs() > 0 && checked.elapsed() >= interval {
+                            checked = std::time::Instant::now();
+                            if !utils::health_check(config, addr, &mut check_failed_times) {
+                                break 'wait;
                             }
                         }
+                        if self.should_failback(config, &mut failback_checked) {
+                            upstream = 0;
+                            break 'wait;
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(200));
+                    }
+                    if started.elapsed() >= config.get_probe_stable_period() {
+                        backoff.reset();
                     }
-                    std::thread::sleep(std::time::Duration::from_secs(
-                        config.get_probe_check_interval() as u64,
+                }
+                Err(e) => {
+                    failed_times += 1;
+                    exit_code = error::get_exit_code(&e);
+                    log(format!(
+                        "{} restart {}th error happend, {}",
+                        addr, failed_times, e
                     ));
+                    if upstreams.len() > 1 {
+                        let next = (upstream + 1) % upstreams.len();
+                        log(format!(
+                            "{} upstream {} failed, fall through to {}",
+                            addr,
+                            upstreams[upstream].name(),
+                            upstreams[next].name()
+                        ));
+                        upstream = next;
+                    }
                 }
             }
-            Err(e) => {
-                anyhow::bail!("Fork failed, {}", e);
+            let signal = utils::get_terminate_signal().unwrap_or(libc::SIGTERM);
+            for child in children.iter_mut() {
+                if let Ok(None) = child.try_wait() {
+                    utils::send_signal(child.id() as usize, signal);
+                }
+            }
+            // SIGKILL the children still alive after stop_timeout
+            let now = std::time::Instant::now();
+            for child in children.iter_mut() {
+                while let Ok(None) = child.try_wait() {
+                    if now.elapsed() >= config.get_stop_timeout() {
+                        log(format!("{} ssh {} stopped by SIGKILL", addr, child.id()));
+                        child.kill().ok();
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                }
+            }
+            State::empty(config, addr).save(config, addr)?;
+            if failed_times >= config.get_probe_failed_times_when_exit() {
+                log(format!(
+                    "{} failed {} times, supervisor will exit.",
+                    addr, failed_times
+                ));
+                State::save_probe(config, addr, None)?;
+                std::process::exit(exit_code);
+            }
+            let delay = backoff.next_delay();
+            if delay.as_secs() > 0 && utils::get_terminate_signal().is_none() {
+                let next_restart = (chrono::Local::now() + chrono::Duration::from_std(delay)?)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string();
+                log(format!(
+                    "{} next restart at {}, in {}s",
+                    addr,
+                    next_restart,
+                    delay.as_secs()
+                ));
+                State {
+                    next_restart: Some(next_restart),
+                    ..State::empty(config, addr)
+                }
+                .save(config, addr)?;
+            }
+            // sleep in small steps to respond to signals
+            let now = std::time::Instant::now();
+            while now.elapsed() < delay && utils::get_terminate_signal().is_none() {
+                std::thread::sleep(std::time::Duration::from_millis(200));
             }
         }
+        log(format!("{} supervisor exit.", addr));
+        State::save_probe(config, addr, None)?;
         Ok(())
     }
 }
+
+/// run `serve` of a local server like the pac server in background, or in the foreground,
+/// recording its pid by `listen`
+pub fn start_server<F: FnOnce()>(
+    config: &Config,
+    listen: &str,
+    foreground: bool,
+    serve: F,
+) -> Result<()> {
+    let log_path = utils::get_log_file(config, listen);
+    if foreground {
+        State::save_probe(
+            config,
+            listen,
+            Some(Process::new(std::process::id() as usize)?),
+        )?;
+        utils::write_log(
+            &log_path,
+            format!("{} start in foreground ...", listen).as_str(),
+        );
+        serve();
+        return Ok(());
+    }
+    match fork() {
+        Ok(Fork::Parent(child)) => {
+            State::save_probe(config, listen, Some(Process::new(child as usize)?))?;
+            utils::write_log(&log_path, format!("{} start ...", listen).as_str());
+        }
+        Ok(Fork::Child) => {
+            serve();
+            std::process::exit(0);
+        }
+        Err(e) => {
+            return Err(SshpError::ForkFailed(format!("Fork failed, {}", e)).into());
+        }
+    }
+    Ok(())
+}
+
+/// stop the local server listening on `listen`
+pub fn stop_server(config: &Config, listen: &str, echo: bool) -> Result<()> {
+    let running = State::load(config, listen).get_alive_probe().is_some();
+    utils::stop_probe_process(config, listen)?;
+    if echo {
+        if running {
+            utils::print_with_color("Stop Success!\n", 34, false);
+        } else {
+            utils::print_with_color("No Process to Kill.\n", 33, false);
+        }
+    }
+    Ok(())
+}
+
+/// bind the http proxy front-end of the tunnel if `http_listen` is set
+fn bind_http_listener(config: &Config) -> Result<Option<std::net::TcpListener>> {
+    match config.get_http_listen() {
+        Some(e) => match std::net::TcpListener::bind(e) {
+            Ok(listener) => Ok(Some(listener)),
+            Err(err) => Err(error::classify_bind_error(
+                &err,
+                format!("Listen http proxy on {} failed, {}", e, err),
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+/// serve the http proxy front-end in background, forwarding through the socks5 addr
+fn serve_http_proxy(
+    listener: Option<std::net::TcpListener>,
+    addr: &str,
+    log_path: &std::path::Path,
+) {
+    if let Some(listener) = listener {
+        let socks_addr = addr.to_string();
+        let log_path = log_path.to_path_buf();
+        std::thread::spawn(move || http_proxy::serve(listener, socks_addr, log_path));
+    }
+}
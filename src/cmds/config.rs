@@ -0,0 +1,174 @@
+#![allow(clippy::new_without_default)]
+
+use crate::cfg::effective::{self, Section};
+use crate::cfg::Config;
+use crate::cmds::SubCmd;
+use crate::utils;
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+use toml::Value;
+
+pub struct ConfigCmd {}
+
+impl SubCmd for ConfigCmd {
+    fn usage<'a>() -> Command<'a> {
+        let config = Arg::new("config")
+            .help("config file path")
+            .short('c')
+            .required(false)
+            .default_value("~/.config/sshp.toml");
+        let set = Arg::new("set")
+            .help("override a config value as key=value, keys without a section are of the tunnel")
+            .long("set")
+            .takes_value(true)
+            .multiple_occurrences(true);
+        Command::new("config")
+            .about("Inspect the config file")
+            .subcommand_required(true)
+            .arg_required_else_help(true)
+            .subcommand(
+                Command::new("check")
+                    .about("Validate the config file without starting anything")
+                    .arg(config.clone())
+                    .arg(set.clone()),
+            )
+            .subcommand(
+                Command::new("show")
+                    .about("Print the effective config with the source of every value")
+                    .arg(
+                        Arg::new("tunnel")
+                            .help("only show the tunnel, default is all the tunnels with pac and router")
+                            .required(false),
+                    )
+                    .arg(Arg::new("json").help("print as json").long("json"))
+                    .arg(config)
+                    .arg(set),
+            )
+    }
+
+    fn handler(&self, arg: &ArgMatches) -> Result<()> {
+        match arg.subcommand() {
+            Some(("check", args)) => self.check(args),
+            Some(("show", args)) => self.show(args),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ConfigCmd {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    /// loading validates the config
+    fn check(&self, arg: &ArgMatches) -> Result<()> {
+        let sets: Vec<&str> = arg
+            .values_of("set")
+            .map(|e| e.collect())
+            .unwrap_or_default();
+        let config = Config::loads_with(arg.value_of("config"), &sets, None)?;
+        utils::print_with_color("Config is valid, tunnels: ", 32, false);
+        utils::print_with_color(config.get_tunnel_names().join(", ").as_str(), 37, true);
+        println!();
+        Ok(())
+    }
+
+    fn show(&self, arg: &ArgMatches) -> Result<()> {
+        let sets: Vec<&str> = arg
+            .values_of("set")
+            .map(|e| e.collect())
+            .unwrap_or_default();
+        let mut config = Config::loads_with(arg.value_of("config"), &sets, arg.value_of("tunnel"))?;
+        let sections = effective::sections(&mut config, arg.value_of("tunnel"))?;
+        if arg.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&to_json(sections))?);
+        } else {
+            print!("{}", to_toml(&sections));
+        }
+        Ok(())
+    }
+}
+
+/// bare keys as is, the others quoted
+fn toml_key(key: &str) -> String {
+    if !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return key.to_string();
+    }
+    Value::String(key.to_string()).to_string()
+}
+
+/// a value on one line, tables inline
+fn toml_inline(value: &Value) -> String {
+    match value {
+        Value::Array(e) => format!(
+            "[{}]",
+            e.iter()
+                .map(toml_inline)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        Value::Table(e) => format!(
+            "{{ {} }}",
+            e.iter()
+                .map(|(k, v)| format!("{} = {}", toml_key(k), toml_inline(v)))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+        e => e.to_string(),
+    }
+}
+
+/// toml with the source of every value in a trailing comment
+fn to_toml(sections: &[Section]) -> String {
+    let mut res = String::new();
+    for section in sections {
+        if !section.path.is_empty() {
+            let path: Vec<String> = section.path.iter().map(|e| toml_key(e)).collect();
+            res.push_str(format!("\n[{}]\n", path.join(".")).as_str());
+        }
+        let lines: Vec<(String, String)> = section
+            .settings
+            .iter()
+            .map(|(k, v)| {
+                (
+                    format!("{} = {}", toml_key(k), toml_inline(&v.value)),
+                    v.source.to_string(),
+                )
+            })
+            .collect();
+        // align the comments, except for the long lines
+        let width = lines
+            .iter()
+            .map(|e| e.0.len())
+            .filter(|e| *e <= 60)
+            .max()
+            .unwrap_or_default();
+        for (line, source) in lines {
+            res.push_str(format!("{:width$}  # {}\n", line, source, width = width).as_str());
+        }
+    }
+    res
+}
+
+/// sections nested by their path, every value as `{"value": .., "source": ..}`
+fn to_json(sections: Vec<Section>) -> serde_json::Value {
+    let mut res = serde_json::Map::new();
+    for section in sections {
+        let mut table = &mut res;
+        for e in section.path {
+            table = table
+                .entry(e)
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()))
+                .as_object_mut()
+                .unwrap();
+        }
+        for (k, v) in section.settings {
+            table.insert(k, serde_json::json!(v));
+        }
+    }
+    serde_json::Value::Object(res)
+}
@@ -1,12 +1,12 @@
 #![allow(clippy::new_without_default)]
 
-use crate::cfg::Config;
+use crate::cfg::{self, Config, Hop};
+use crate::error::SshpError;
 use crate::utils;
 use anyhow::Result;
 use clap::{Arg, ArgMatches, Command};
 
-use crate::cmds::{Start, SubCmd};
-use std::io::prelude::*;
+use crate::cmds::{SshCommand, Start, SubCmd};
 
 pub struct DynamicProxy {}
 
@@ -22,6 +22,16 @@ impl SubCmd for DynamicProxy {
                     .default_value("start")
                     .possible_values(vec!["start", "stop", "restart"]),
             )
+            .arg(
+                Arg::new("tunnel")
+                    .help("tunnel name in [tunnels], default is the [dynamic_proxy] section")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("foreground")
+                    .help("stay in the foreground as the supervisor of ssh, for systemd and containers")
+                    .long("foreground"),
+            )
             .arg(
                 Arg::new("config")
                     .help("config file path")
@@ -29,24 +39,52 @@ impl SubCmd for DynamicProxy {
                     .required(false)
                     .default_value("~/.config/sshp.toml"),
             )
+            .arg(
+                Arg::new("set")
+                    .help("override a config value as key=value, keys without a section are of the tunnel")
+                    .long("set")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
     }
 
     fn handler(&self, arg: &ArgMatches) -> Result<()> {
-        let config = Config::loads(arg.value_of("config"))?;
+        let tunnel = arg.value_of("tunnel").unwrap_or(cfg::DYNAMIC_PROXY);
+        let sets: Vec<&str> = arg
+            .values_of("set")
+            .map(|e| e.collect())
+            .unwrap_or_default();
+        let mut config = Config::loads_with(arg.value_of("config"), &sets, Some(tunnel))?;
+        config.select_tunnel(tunnel)?;
+        if config.get_tunnel_type() != "dynamic" {
+            return Err(SshpError::ConfigInvalid(format!(
+                "Tunnel `{}` is not a dynamic proxy tunnel",
+                tunnel
+            ))
+            .into());
+        }
         let addr = config.get_dynamic_local_addr();
         match arg.value_of("operation").unwrap() {
             "start" => {
-                utils::stop_probe_process(addr)?;
-                self.start_with_probe(&config, addr)?;
+                utils::stop_probe_process(&config, addr)?;
+                if arg.is_present("foreground") {
+                    self.start_foreground(&config, addr)?;
+                } else {
+                    self.start_with_probe(&config, addr)?;
+                }
             }
             "stop" => {
-                utils::stop_probe_process(addr)?;
-                self.stop(addr, true)?;
+                utils::stop_probe_process(&config, addr)?;
+                self.stop(&config, true)?;
             }
             "restart" => {
-                utils::stop_probe_process(addr)?;
-                self.stop(addr, true)?;
-                self.start_with_probe(&config, addr)?;
+                utils::stop_probe_process(&config, addr)?;
+                self.stop(&config, true)?;
+                if arg.is_present("foreground") {
+                    self.start_foreground(&config, addr)?;
+                } else {
+                    self.start_with_probe(&config, addr)?;
+                }
             }
             _ => {}
         }
@@ -55,64 +93,46 @@ impl SubCmd for DynamicProxy {
 }
 
 impl Start for DynamicProxy {
-    fn start(&self, config: &Config, echo: bool) -> Result<()> {
-        let remote = format!(
-            "{}@{}",
-            config.get_dynamic_remote_user(),
-            config.get_dynamic_remote_ip(),
-        );
-        let addr = config.get_dynamic_local_addr();
-        let mut child = std::process::Command::new("ssh")
-            .args(vec![
-                "-CNf",
-                "-o",
-                format!(
-                    "ServerAliveInterval={}",
-                    config.get_dynamic_heart_beat_interval()
-                )
-                .as_str(),
-                "-o",
-                "StrictHostKeyChecking=no",
-                "-D",
-                addr,
-                remote.as_str(),
-                "-p",
-                config.get_dynamic_remote_port().to_string().as_str(),
-            ])
-            .stderr(std::process::Stdio::piped())
-            .spawn()?;
-        let (tx, rx) = std::sync::mpsc::channel();
-        let mut stderr = child.stderr.take().unwrap();
-        std::thread::spawn(move || {
-            let mut s = vec![0; 1024 * 10];
-            // sleep 1s to get all error message
-            std::thread::sleep(std::time::Duration::from_millis(1000));
-            let n = stderr.read(&mut s).unwrap();
-            if tx
-                .send(String::from_utf8_lossy(&s[..n]).to_string())
-                .is_err()
-            {}
-        });
-        let status = child.wait()?;
-        if let Ok(e) = rx.recv_timeout(std::time::Duration::from_secs(2)) {
-            if e.contains("failed") || e.contains("Address already in use") || !status.success() {
-                self.stop(addr, echo)?;
-                anyhow::bail!("Open dynamic proxy failed: \n{}", e.trim());
-            }
+    fn get_addr<'a>(&self, config: &'a Config) -> &'a str {
+        config.get_dynamic_local_addr()
+    }
+
+    fn get_upstreams(&self, config: &Config) -> Result<Vec<Hop>> {
+        if config.get_ssh_config_host().is_some() {
+            return Ok(Vec::new());
         }
-        if status.success() {
-            if !utils::check_result(utils::check(addr), addr, echo) {
-                self.stop(addr, echo)?;
-                anyhow::bail!("curl check {} failed.", addr);
+        config.get_dynamic_remote_hosts()
+    }
+
+    fn commands(&self, config: &Config, upstream: usize) -> Result<Vec<SshCommand>> {
+        let addr = config.get_dynamic_local_addr();
+        let mut args = vec!["-CN".to_string()];
+        args.extend(config.get_ssh_extra_args());
+        args.extend(vec![
+            "-o".to_string(),
+            format!(
+                "ServerAliveInterval={}",
+                config.get_dynamic_heart_beat_interval()
+            ),
+        ]);
+        args.extend(config.get_host_key_args(None)?);
+        args.extend(vec!["-D".to_string(), addr.to_string()]);
+        match config.get_ssh_config_host() {
+            Some(host) => args.push(host.to_string()),
+            None => {
+                let hosts = config.get_dynamic_remote_hosts()?;
+                let host = &hosts[upstream.min(hosts.len() - 1)];
+                args.extend(vec![
+                    format!("{}@{}", host.user, host.ip),
+                    "-p".to_string(),
+                    host.port.to_string(),
+                ])
             }
-        } else {
-            self.stop(addr, echo)?;
-            anyhow::bail!(
-                "Open dynamic proxy failed, status code is {}",
-                status.to_string()
-            );
         }
-        Ok(())
+        Ok(vec![SshCommand {
+            args,
+            listens: vec![addr.to_string()],
+        }])
     }
 }
 
@@ -120,22 +140,4 @@ impl DynamicProxy {
     pub fn new() -> Self {
         Self {}
     }
-
-    fn stop(&self, addr: &str, echo: bool) -> Result<()> {
-        let pids = utils::get_pids(addr)?;
-        for pid in pids.as_slice() {
-            #[cfg(target_family = "unix")]
-            utils::kill_child_by_pid(pid.to_owned())?;
-            #[cfg(target_os = "windows")]
-            utils::kill_child_by_pid_windows(pid)?;
-        }
-        if echo {
-            if !pids.is_empty() {
-                utils::print_with_color("Stop Success!\n", 34, false);
-            } else {
-                utils::print_with_color("No Process to Kill.\n", 33, false);
-            }
-        }
-        Ok(())
-    }
 }
@@ -0,0 +1,160 @@
+#![allow(clippy::new_without_default)]
+
+use crate::cfg::{self, Config};
+use crate::error::SshpError;
+use crate::utils;
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+
+use crate::cmds::{SshCommand, Start, SubCmd};
+
+pub struct Forward {}
+
+impl SubCmd for Forward {
+    fn usage<'a>() -> Command<'a> {
+        Command::new("forward")
+            .about("Open SSH Local or Remote Port Forwards")
+            .visible_alias("f")
+            .arg(
+                Arg::new("operation")
+                    .help("operation type to operate the forwards")
+                    .short('t')
+                    .default_value("start")
+                    .possible_values(vec!["start", "stop", "restart"]),
+            )
+            .arg(
+                Arg::new("tunnel")
+                    .help("tunnel name in [tunnels] with type `local` or `remote`")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("foreground")
+                    .help("stay in the foreground as the supervisor of ssh, for systemd and containers")
+                    .long("foreground"),
+            )
+            .arg(
+                Arg::new("config")
+                    .help("config file path")
+                    .short('c')
+                    .required(false)
+                    .default_value("~/.config/sshp.toml"),
+            )
+            .arg(
+                Arg::new("set")
+                    .help("override a config value as key=value, keys without a section are of the tunnel")
+                    .long("set")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+    }
+
+    fn handler(&self, arg: &ArgMatches) -> Result<()> {
+        let name = arg.value_of("tunnel").unwrap();
+        let sets: Vec<&str> = arg
+            .values_of("set")
+            .map(|e| e.collect())
+            .unwrap_or_default();
+        let mut config = Config::loads_with(arg.value_of("config"), &sets, Some(name))?;
+        config.select_tunnel(name)?;
+        if !matches!(config.get_tunnel_type(), "local" | "remote") {
+            return Err(SshpError::ConfigInvalid(format!(
+                "Tunnel `{}` is not a local or remote forward tunnel",
+                name
+            ))
+            .into());
+        }
+        let addr = config.get_tunnel_addr();
+        match arg.value_of("operation").unwrap() {
+            "start" => {
+                utils::stop_probe_process(&config, addr)?;
+                if arg.is_present("foreground") {
+                    self.start_foreground(&config, addr)?;
+                } else {
+                    self.start_with_probe(&config, addr)?;
+                }
+            }
+            "stop" => {
+                utils::stop_probe_process(&config, addr)?;
+                self.stop(&config, true)?;
+            }
+            "restart" => {
+                utils::stop_probe_process(&config, addr)?;
+                self.stop(&config, true)?;
+                if arg.is_present("foreground") {
+                    self.start_foreground(&config, addr)?;
+                } else {
+                    self.start_with_probe(&config, addr)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Start for Forward {
+    fn get_addr<'a>(&self, config: &'a Config) -> &'a str {
+        config.get_tunnel_addr()
+    }
+
+    fn commands(&self, config: &Config, _upstream: usize) -> Result<Vec<SshCommand>> {
+        let forwards = config.get_forwards()?;
+        let (flag, listens) = match config.get_tunnel_type() {
+            "local" => (
+                "-L",
+                forwards
+                    .iter()
+                    .map(|e| format!("{}:{}", cfg::bracket(e.get_connect_host()), e.port))
+                    .collect(),
+            ),
+            _ => ("-R", Vec::new()),
+        };
+        let mut args = vec!["-N".to_string()];
+        args.extend(config.get_ssh_extra_args());
+        args.extend(vec![
+            "-o".to_string(),
+            format!(
+                "ServerAliveInterval={}",
+                config.get_forward_heart_beat_interval()
+            ),
+            // exit instead of running without the forwards
+            "-o".to_string(),
+            "ExitOnForwardFailure=yes".to_string(),
+        ]);
+        args.extend(config.get_host_key_args(None)?);
+        for forward in forwards.iter() {
+            args.extend(vec![flag.to_string(), forward.spec()]);
+        }
+        args.extend(config.get_forward_target_args());
+        Ok(vec![SshCommand { args, listens }])
+    }
+}
+
+impl Forward {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::Config;
+    use crate::cmds::forward::Forward;
+    use crate::cmds::Start;
+
+    #[test]
+    fn test_listens() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [tunnels.db]
+            type = "local"
+            remote_ip = "192.168.8.22"
+            forwards = ["[::1]:5432:db.internal:5432", "6379:redis.internal:6379"]
+            "#,
+        )
+        .unwrap();
+        config.select_tunnel("db").unwrap();
+        let commands = Forward::new().commands(&config, 0).unwrap();
+        assert_eq!(commands[0].listens, vec!["[::1]:5432", "localhost:6379"]);
+    }
+}
@@ -1,11 +1,12 @@
 #![allow(clippy::new_without_default)]
-use crate::cfg::Config;
+use crate::cfg::{self, Config, Hop};
+use crate::error::SshpError;
+use crate::known_hosts;
 use crate::utils;
 use anyhow::Result;
 use clap::{Arg, ArgMatches, Command};
 
-use crate::cmds::{Start, SubCmd};
-use std::io::prelude::*;
+use crate::cmds::{SshCommand, Start, SubCmd};
 
 pub struct MultiDynamicProxy {}
 
@@ -21,6 +22,16 @@ impl SubCmd for MultiDynamicProxy {
                     .default_value("start")
                     .possible_values(vec!["start", "stop", "restart"]),
             )
+            .arg(
+                Arg::new("tunnel")
+                    .help("tunnel name in [tunnels], default is the [multi_proxy] section")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("foreground")
+                    .help("stay in the foreground as the supervisor of ssh, for systemd and containers")
+                    .long("foreground"),
+            )
             .arg(
                 Arg::new("config")
                     .help("config file path")
@@ -28,25 +39,52 @@ impl SubCmd for MultiDynamicProxy {
                     .required(false)
                     .default_value("~/.config/sshp.toml"),
             )
+            .arg(
+                Arg::new("set")
+                    .help("override a config value as key=value, keys without a section are of the tunnel")
+                    .long("set")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
     }
 
     fn handler(&self, arg: &ArgMatches) -> Result<()> {
-        let config = Config::loads(arg.value_of("config"))?;
+        let tunnel = arg.value_of("tunnel").unwrap_or(cfg::MULTI_PROXY);
+        let sets: Vec<&str> = arg
+            .values_of("set")
+            .map(|e| e.collect())
+            .unwrap_or_default();
+        let mut config = Config::loads_with(arg.value_of("config"), &sets, Some(tunnel))?;
+        config.select_tunnel(tunnel)?;
+        if config.get_tunnel_type() != "multi" {
+            return Err(SshpError::ConfigInvalid(format!(
+                "Tunnel `{}` is not a multi dynamic proxy tunnel",
+                tunnel
+            ))
+            .into());
+        }
         let addr = config.get_multi_dynamic_local_addr();
-        let forward = self.get_forward_addr(&config);
         match arg.value_of("operation").unwrap() {
             "start" => {
-                utils::stop_probe_process(addr)?;
-                self.start_with_probe(&config, addr)?;
+                utils::stop_probe_process(&config, addr)?;
+                if arg.is_present("foreground") {
+                    self.start_foreground(&config, addr)?;
+                } else {
+                    self.start_with_probe(&config, addr)?;
+                }
             }
             "stop" => {
-                utils::stop_probe_process(addr)?;
-                self.stop(addr, forward.as_str(), true)?;
+                utils::stop_probe_process(&config, addr)?;
+                self.stop(&config, true)?;
             }
             "restart" => {
-                utils::stop_probe_process(addr)?;
-                self.stop(addr, forward.as_str(), true)?;
-                self.start_with_probe(&config, addr)?;
+                utils::stop_probe_process(&config, addr)?;
+                self.stop(&config, true)?;
+                if arg.is_present("foreground") {
+                    self.start_foreground(&config, addr)?;
+                } else {
+                    self.start_with_probe(&config, addr)?;
+                }
             }
             _ => {}
         }
@@ -55,139 +93,73 @@ impl SubCmd for MultiDynamicProxy {
 }
 
 impl Start for MultiDynamicProxy {
-    fn start(&self, config: &Config, echo: bool) -> Result<()> {
-        let remote = format!(
-            "{}:{}",
-            config.get_multi_dynamic_remote_ip(),
-            config.get_multi_dynamic_remote_port(),
-        );
-        let forward = self.get_forward_addr(config);
-        let forward = forward.as_str();
-        let available_port = match config.get_multi_dynamic_local_forward_port() {
-            Some(e) => e,
-            None => utils::get_avaliable_port() as usize,
+    fn get_addr<'a>(&self, config: &'a Config) -> &'a str {
+        config.get_multi_dynamic_local_addr()
+    }
+
+    fn commands(&self, config: &Config, _upstream: usize) -> Result<Vec<SshCommand>> {
+        if config.get_multi_dynamic_proxy_jump() {
+            return self.proxy_jump_commands(config);
+        }
+        let hops = config.get_multi_dynamic_hops();
+        let addr = config.get_multi_dynamic_local_addr();
+        // the forwards between the hops listen on the loopback of the family of local_addr
+        let loopback = addr.parse::<cfg::Addr>()?.get_loopback();
+        let mut available_ports = utils::get_avaliable_ports(loopback, hops.len() - 1).into_iter();
+        let mut commands = Vec::new();
+        // the first hop is reached directly, the others through the local forward of the previous one
+        let mut target = match config.get_ssh_config_host() {
+            Some(host) => vec![host.to_string()],
+            None => vec![
+                format!("{}@{}", hops[0].user, hops[0].ip),
+                "-p".to_string(),
+                hops[0].port.to_string(),
+            ],
         };
-        let mut local_forward = std::process::Command::new("ssh")
-            .args(vec![
-                "-CNf",
-                "-o",
+        for (i, next) in hops.iter().enumerate().skip(1) {
+            let available_port = match config.get_multi_dynamic_local_forward_port() {
+                Some(e) if i == 1 => e,
+                _ => match available_ports.next() {
+                    Some(e) => e as usize,
+                    None => {
+                        return Err(SshpError::AddressInUse(
+                            "No available port for the local forward".to_string(),
+                        )
+                        .into())
+                    }
+                },
+            };
+            let mut args = self.ssh_args(config, &hops[i - 1], i > 1)?;
+            args.extend(vec![
+                "-L".to_string(),
                 format!(
-                    "ServerAliveInterval={}",
-                    config.get_multi_dynamic_heart_beat_interval()
-                )
-                .as_str(),
-                "-o",
-                "StrictHostKeyChecking=no",
-                "-L",
-                format!("{}:{}", available_port, remote).as_str(),
-                forward,
-                "-p",
-                config.get_multi_dynamic_forward_port().to_string().as_str(),
-            ])
-            .stderr(std::process::Stdio::piped())
-            .spawn()?;
-        let (tx, rx) = std::sync::mpsc::channel();
-        let mut stderr = local_forward.stderr.take().unwrap();
-        std::thread::spawn(move || {
-            let mut s = vec![0; 1024 * 10];
-            // sleep 0.5s to get all error message
-            std::thread::sleep(std::time::Duration::from_millis(500));
-            let n = stderr.read(&mut s).unwrap();
-            if tx
-                .send(String::from_utf8_lossy(&s[..n]).to_string())
-                .is_err()
-            {};
-        });
-        let status = local_forward.wait()?;
-        let addr = config.get_multi_dynamic_local_addr();
-        match rx.recv_timeout(std::time::Duration::from_secs(2)) {
-            Ok(e) => {
-                if e.contains("failed") || e.contains("Address already in use") || !status.success()
-                {
-                    self.stop(addr, forward, echo)?;
-                    anyhow::bail!("Open multi dynamic proxy failed: \n{}", e.trim());
-                }
-            }
-            Err(e) => {
-                if e == std::sync::mpsc::RecvTimeoutError::Disconnected {
-                    self.stop(addr, forward, echo)?;
-                    anyhow::bail!("receive data from thread error happend, {}", e);
-                }
-            }
-        }
-        if status.success() {
-            // dynamic proxy
-            let remote_user_local = format!(
-                "{}@{}",
-                config.get_multi_dynamic_remote_user(),
-                config
-                    .get_multi_dynamic_local_addr()
-                    .split(':')
-                    .collect::<Vec<&str>>()[0]
-            );
-            let mut dynamic_proxy = std::process::Command::new("ssh")
-                .args(vec![
-                    "-CNf",
-                    "-o",
-                    format!(
-                        "ServerAliveInterval={}",
-                        config.get_multi_dynamic_heart_beat_interval()
-                    )
-                    .as_str(),
-                    "-D",
-                    addr,
-                    remote_user_local.as_str(),
-                    "-p",
-                    available_port.to_string().as_str(),
-                    "-o",
-                    "StrictHostKeyChecking=no",
-                ])
-                .stderr(std::process::Stdio::piped())
-                .stdin(std::process::Stdio::piped())
-                .stdout(std::process::Stdio::piped())
-                .spawn()?;
-            let (tx, rx) = std::sync::mpsc::channel();
-            let mut stderr = dynamic_proxy.stderr.take().unwrap();
-            std::thread::spawn(move || {
-                // sleep 0.5s to get all error message and other message
-                std::thread::sleep(std::time::Duration::from_millis(500));
-                let mut s = vec![0; 1024 * 10];
-                let n = stderr.read(&mut s).unwrap();
-                if tx
-                    .send(String::from_utf8_lossy(&s[..n]).to_string())
-                    .is_err()
-                {}
+                    "{}:{}:{}:{}",
+                    cfg::bracket(loopback),
+                    available_port,
+                    cfg::bracket(next.ip.as_str()),
+                    next.port
+                ),
+            ]);
+            args.extend(target);
+            commands.push(SshCommand {
+                args,
+                listens: vec![format!("{}:{}", cfg::bracket(loopback), available_port)],
             });
-            let status = dynamic_proxy.wait()?;
-            match rx.recv_timeout(std::time::Duration::from_secs(2)) {
-                Ok(e) => {
-                    if e.contains("failed")
-                        || e.contains("Address already in use")
-                        || !status.success()
-                    {
-                        self.stop(addr, forward, echo)?;
-                        anyhow::bail!("Open multi dynamic proxy failed:\n{}", e.trim());
-                    }
-                }
-                Err(e) => {
-                    if e == std::sync::mpsc::RecvTimeoutError::Disconnected {
-                        self.stop(addr, forward, echo)?;
-                        anyhow::bail!("receive data from thread error happend, {}", e);
-                    }
-                }
-            }
-            if !utils::check_result(utils::check(addr), addr, echo) {
-                self.stop(addr, forward, echo)?;
-                anyhow::bail!("curl check {} failed.", addr);
-            }
-        } else {
-            self.stop(addr, forward, echo)?;
-            anyhow::bail!(
-                "Open dynamic proxy failed, status code is {}",
-                status.to_string()
-            );
+            target = vec![
+                format!("{}@{}", next.user, loopback),
+                "-p".to_string(),
+                available_port.to_string(),
+            ];
         }
-        Ok(())
+        // dynamic proxy on the last hop
+        let mut args = self.ssh_args(config, &hops[hops.len() - 1], hops.len() > 1)?;
+        args.extend(vec!["-D".to_string(), addr.to_string()]);
+        args.extend(target);
+        commands.push(SshCommand {
+            args,
+            listens: vec![addr.to_string()],
+        });
+        Ok(commands)
     }
 }
 
@@ -195,31 +167,133 @@ impl MultiDynamicProxy {
     pub fn new() -> Self {
         Self {}
     }
-    fn get_forward_addr(&self, config: &Config) -> String {
-        format!(
-            "{}@{}",
-            config.get_multi_dynamic_forward_user(),
-            config.get_multi_dynamic_forward_ip()
-        )
-    }
 
-    fn stop(&self, addr: &str, forward: &str, echo: bool) -> Result<()> {
-        let mut pids = utils::get_pids(addr)?;
-        let pid2 = utils::get_pids(forward)?;
-        pids.extend(pid2);
-        for pid in pids.as_slice() {
-            #[cfg(target_family = "unix")]
-            utils::kill_child_by_pid(pid.to_owned())?;
-            #[cfg(target_os = "windows")]
-            utils::kill_child_by_pid_windows(pid)?;
-        }
-        if echo {
-            if !pids.is_empty() {
-                utils::print_with_color("Stop Success!\n", 34, false);
-            } else {
-                utils::print_with_color("No Process to Kill.\n", 33, false);
+    /// a single ssh to the last hop opening the dynamic proxy, every hop is reached by a
+    /// `ProxyCommand` ssh through the previous one, unlike `ssh -J` it applies the host key
+    /// settings and identity_file to the jump hops
+    fn proxy_jump_commands(&self, config: &Config) -> Result<Vec<SshCommand>> {
+        let hops = config.get_multi_dynamic_hops();
+        let (last, jumps) = hops.split_last().unwrap();
+        let mut proxy: Option<String> = None;
+        for (i, hop) in jumps.iter().enumerate() {
+            let mut words = vec!["ssh".to_string()];
+            words.extend(config.get_ssh_extra_args());
+            words.extend(config.get_host_key_args(None)?);
+            if let Some(ref e) = hop.identity_file {
+                words.extend(vec!["-i".to_string(), e.to_string()]);
+            }
+            if let Some(e) = proxy {
+                words.extend(vec!["-o".to_string(), format!("ProxyCommand={}", e)]);
             }
+            let target = match config.get_ssh_config_host() {
+                Some(host) if i == 0 => vec![host.to_string()],
+                _ => vec![
+                    format!("{}@{}", hop.user, hop.ip),
+                    "-p".to_string(),
+                    hop.port.to_string(),
+                ],
+            };
+            // ssh expands the `%h` and `%p` of its ProxyCommand, the `%`s of the values and
+            // of the nested ones are escaped to reach the ssh running them as is
+            let quote = |e: &String| shell_quote(e.replace('%', "%%").as_str());
+            let mut words: Vec<String> = words.iter().map(quote).collect();
+            words.extend(vec!["-W".to_string(), shell_quote("[%h]:%p")]);
+            words.extend(target.iter().map(quote));
+            proxy = Some(words.join(" "));
         }
-        Ok(())
+        let addr = config.get_multi_dynamic_local_addr();
+        let mut args = self.ssh_args(config, last, false)?;
+        if let Some(e) = proxy {
+            args.extend(vec!["-o".to_string(), format!("ProxyCommand={}", e)]);
+        }
+        args.extend(vec![
+            "-D".to_string(),
+            addr.to_string(),
+            format!("{}@{}", last.user, last.ip),
+            "-p".to_string(),
+            last.port.to_string(),
+        ]);
+        Ok(vec![SshCommand {
+            args,
+            listens: vec![addr.to_string()],
+        }])
+    }
+
+    /// common args of the ssh to hop, `via_forward` if the hop is reached through the local
+    /// forward of the previous one
+    fn ssh_args(&self, config: &Config, hop: &Hop, via_forward: bool) -> Result<Vec<String>> {
+        let mut args = vec!["-CN".to_string()];
+        args.extend(config.get_ssh_extra_args());
+        args.extend(vec![
+            "-o".to_string(),
+            format!(
+                "ServerAliveInterval={}",
+                config.get_multi_dynamic_heart_beat_interval()
+            ),
+        ]);
+        let alias = if via_forward {
+            Some(known_hosts::host_name(hop.ip.as_str(), hop.port))
+        } else {
+            None
+        };
+        args.extend(config.get_host_key_args(alias)?);
+        if let Some(ref e) = hop.identity_file {
+            args.extend(vec!["-i".to_string(), e.to_string()]);
+        }
+        Ok(args)
+    }
+}
+
+/// quote `s` as one word for the shell running a `ProxyCommand`
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "@%_+=:,./-".contains(c))
+    {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::Config;
+    use crate::cmds::multi_proxy::MultiDynamicProxy;
+    use crate::cmds::Start;
+
+    #[test]
+    fn test_proxy_jump() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [multi_proxy]
+            local_addr = "127.0.0.1:50002"
+            proxy_jump = true
+            host_key_policy = "strict"
+            hops = [
+                { ip = "39.0.0.208", identity_file = "/tmp/id 100%" },
+                { ip = "2001:db8::1", user = "ops", port = 2222, host_key_fingerprint = "SHA256:abc" },
+                { ip = "10.20.0.8", user = "app", identity_file = "/tmp/id_app" },
+            ]
+            "#,
+        )
+        .unwrap();
+        config.select_tunnel("multi_proxy").unwrap();
+        let commands = MultiDynamicProxy::new().commands(&config, 0).unwrap();
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].listens, vec!["127.0.0.1:50002"]);
+        let args = &commands[0].args;
+        assert!(args.join(" ").contains("-i /tmp/id_app"));
+        assert!(args
+            .join(" ")
+            .ends_with("-D 127.0.0.1:50002 app@10.20.0.8 -p 22"));
+        let proxy = args
+            .iter()
+            .find_map(|e| e.strip_prefix("ProxyCommand="))
+            .unwrap();
+        assert!(proxy.starts_with("ssh -o StrictHostKeyChecking=yes"));
+        assert!(proxy.ends_with("-W '[%h]:%p' ops@2001:db8::1 -p 2222"));
+        // the ProxyCommand to the first hop is nested and expanded once more
+        assert!(proxy
+            .contains(r#"-i '\''/tmp/id 100%%%%'\'' -W '\''[%%h]:%%p'\'' root@39.0.0.208 -p 22'"#));
     }
 }
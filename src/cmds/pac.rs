@@ -0,0 +1,83 @@
+#![allow(clippy::new_without_default)]
+
+use crate::cfg::Config;
+use crate::cmds::{self, SubCmd};
+use crate::error;
+use crate::pac;
+use crate::utils;
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+
+pub struct Pac {}
+
+impl SubCmd for Pac {
+    fn usage<'a>() -> Command<'a> {
+        Command::new("pac")
+            .about("Serve the PAC file routing hosts to the tunnels by the rules in [pac]")
+            .visible_alias("p")
+            .arg(
+                Arg::new("operation")
+                    .help("operation type to operate the pac server, `print` shows the pac file")
+                    .short('t')
+                    .default_value("start")
+                    .possible_values(vec!["start", "stop", "restart", "print"]),
+            )
+            .arg(
+                Arg::new("foreground")
+                    .help("stay in the foreground, for systemd and containers")
+                    .long("foreground"),
+            )
+            .arg(
+                Arg::new("config")
+                    .help("config file path")
+                    .short('c')
+                    .required(false)
+                    .default_value("~/.config/sshp.toml"),
+            )
+    }
+
+    fn handler(&self, arg: &ArgMatches) -> Result<()> {
+        let config = Config::loads(arg.value_of("config"))?;
+        let listen = config.get_pac_listen()?;
+        match arg.value_of("operation").unwrap() {
+            "start" => {
+                cmds::stop_server(&config, listen, false)?;
+                self.start(&config, listen, arg.is_present("foreground"))?;
+            }
+            "stop" => cmds::stop_server(&config, listen, true)?,
+            "restart" => {
+                cmds::stop_server(&config, listen, true)?;
+                self.start(&config, listen, arg.is_present("foreground"))?;
+            }
+            "print" => print!("{}", pac::generate(&config)?),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Pac {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn start(&self, config: &Config, listen: &str, foreground: bool) -> Result<()> {
+        let body = pac::generate(config)?;
+        let log_path = utils::get_log_file(config, listen);
+        let listener = match std::net::TcpListener::bind(listen) {
+            Ok(e) => e,
+            Err(e) => {
+                return Err(error::classify_bind_error(
+                    &e,
+                    format!("Listen pac server on {} failed, {}", listen, e),
+                ))
+            }
+        };
+        utils::print_with_color("Serve PAC Success, url is ", 32, false);
+        utils::print_with_color(format!("http://{}/proxy.pac", listen).as_str(), 37, true);
+        println!();
+        cmds::start_server(config, listen, foreground, move || {
+            pac::serve(listener, body, log_path)
+        })
+    }
+}
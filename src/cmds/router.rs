@@ -0,0 +1,84 @@
+#![allow(clippy::new_without_default)]
+
+use crate::cfg::Config;
+use crate::cmds::{self, SubCmd};
+use crate::error;
+use crate::router;
+use crate::utils;
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+
+pub struct Router {}
+
+impl SubCmd for Router {
+    fn usage<'a>() -> Command<'a> {
+        Command::new("router")
+            .about(
+                "Open a SOCKS5 proxy routing connections to the tunnels by the rules in [router]",
+            )
+            .visible_alias("r")
+            .arg(
+                Arg::new("operation")
+                    .help("operation type to operate the router")
+                    .short('t')
+                    .default_value("start")
+                    .possible_values(vec!["start", "stop", "restart"]),
+            )
+            .arg(
+                Arg::new("foreground")
+                    .help("stay in the foreground, for systemd and containers")
+                    .long("foreground"),
+            )
+            .arg(
+                Arg::new("config")
+                    .help("config file path")
+                    .short('c')
+                    .required(false)
+                    .default_value("~/.config/sshp.toml"),
+            )
+    }
+
+    fn handler(&self, arg: &ArgMatches) -> Result<()> {
+        let config = Config::loads(arg.value_of("config"))?;
+        let listen = config.get_router_listen()?;
+        match arg.value_of("operation").unwrap() {
+            "start" => {
+                cmds::stop_server(&config, listen, false)?;
+                self.start(&config, listen, arg.is_present("foreground"))?;
+            }
+            "stop" => cmds::stop_server(&config, listen, true)?,
+            "restart" => {
+                cmds::stop_server(&config, listen, true)?;
+                self.start(&config, listen, arg.is_present("foreground"))?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn start(&self, config: &Config, listen: &str, foreground: bool) -> Result<()> {
+        let router = router::Router::new(config)?;
+        let log_path = utils::get_log_file(config, listen);
+        let listener = match std::net::TcpListener::bind(listen) {
+            Ok(e) => e,
+            Err(e) => {
+                return Err(error::classify_bind_error(
+                    &e,
+                    format!("Listen router on {} failed, {}", listen, e),
+                ))
+            }
+        };
+        utils::print_with_color("Open Router Success, listen addr is ", 32, false);
+        utils::print_with_color(listen, 37, true);
+        println!();
+        cmds::start_server(config, listen, foreground, move || {
+            router::serve(listener, router, log_path)
+        })
+    }
+}
@@ -0,0 +1,168 @@
+#![allow(clippy::new_without_default)]
+
+use crate::cfg::Config;
+use crate::cmds::SubCmd;
+use crate::state::State;
+use crate::utils;
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+use serde::Serialize;
+
+pub struct Status {}
+
+#[derive(Debug, Serialize)]
+pub struct TunnelStatus {
+    name: String,
+    #[serde(rename = "type")]
+    tunnel_type: String,
+    listen_addr: String,
+    http_listen: Option<String>,
+    pids: Vec<usize>,
+    probe_pid: Option<usize>,
+    upstream: Option<String>,
+    last_restart: Option<String>,
+    next_restart: Option<String>,
+    reachable: bool,
+    latency_ms: Option<u128>,
+    error: Option<String>,
+}
+
+impl SubCmd for Status {
+    fn usage<'a>() -> Command<'a> {
+        Command::new("status")
+            .about("Show the status of the tunnels")
+            .visible_alias("s")
+            .arg(
+                Arg::new("tunnel")
+                    .help("tunnel name, default is all the tunnels")
+                    .required(false),
+            )
+            .arg(
+                Arg::new("json")
+                    .help("print the status as json")
+                    .long("json"),
+            )
+            .arg(
+                Arg::new("config")
+                    .help("config file path")
+                    .short('c')
+                    .required(false)
+                    .default_value("~/.config/sshp.toml"),
+            )
+    }
+
+    fn handler(&self, arg: &ArgMatches) -> Result<()> {
+        let mut config = Config::loads(arg.value_of("config"))?;
+        let names = match arg.value_of("tunnel") {
+            Some(e) => vec![e.to_string()],
+            None => config.get_tunnel_names(),
+        };
+        let mut res = Vec::new();
+        for name in names {
+            config.select_tunnel(name.as_str())?;
+            res.push(self.get_status(&config)?);
+        }
+        if arg.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&res)?);
+        } else {
+            for e in res.iter() {
+                self.print_status(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Status {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn get_status(&self, config: &Config) -> Result<TunnelStatus> {
+        let addr = config.get_tunnel_addr();
+        let listen_addr = match config.get_tunnel_type() {
+            "local" | "remote" => config
+                .get_forwards()?
+                .iter()
+                .map(|e| e.spec())
+                .collect::<Vec<String>>()
+                .join(", "),
+            _ => addr.to_string(),
+        };
+        let state = State::load(config, addr);
+        let pids = state.get_alive_pids();
+        let probe_pid = state.get_alive_probe().map(|e| e.pid);
+        // nothing to check through if no ssh of the tunnel is alive
+        let (latency_ms, error) = match pids.is_empty() {
+            true => (None, Some("not running".to_string())),
+            false => match utils::check(config, addr) {
+                Ok(e) => (Some(e.as_millis()), None),
+                Err(e) => (None, Some(e.to_string())),
+            },
+        };
+        Ok(TunnelStatus {
+            name: config.get_tunnel_name().to_string(),
+            tunnel_type: config.get_tunnel_type().to_string(),
+            listen_addr,
+            http_listen: config.get_http_listen().map(|e| e.to_string()),
+            pids,
+            probe_pid,
+            upstream: state.upstream.clone(),
+            last_restart: utils::get_last_start_time(config, addr),
+            next_restart: state.next_restart.clone(),
+            reachable: latency_ms.is_some(),
+            latency_ms,
+            error,
+        })
+    }
+
+    fn print_status(&self, status: &TunnelStatus) {
+        utils::print_with_color(status.name.as_str(), 37, true);
+        utils::print_with_color(
+            format!(" ({}) {} ", status.tunnel_type, status.listen_addr).as_str(),
+            37,
+            false,
+        );
+        if let Some(e) = status.latency_ms {
+            utils::print_with_color(format!("up {}ms\n", e).as_str(), 32, true);
+        } else {
+            utils::print_with_color("down\n", 31, true);
+        }
+        let pids = status
+            .pids
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<String>>();
+        println!(
+            "  ssh pids:     {}",
+            if pids.is_empty() {
+                "-".to_string()
+            } else {
+                pids.join(", ")
+            }
+        );
+        if let Some(ref e) = status.http_listen {
+            println!("  http listen:  {}", e);
+        }
+        println!(
+            "  probe pid:    {}",
+            status
+                .probe_pid
+                .map(|e| e.to_string())
+                .unwrap_or_else(|| "-".to_string())
+        );
+        if let Some(ref e) = status.upstream {
+            println!("  upstream:     {}", e);
+        }
+        println!(
+            "  last restart: {}",
+            status.last_restart.as_deref().unwrap_or("-")
+        );
+        if let Some(ref e) = status.next_restart {
+            println!("  next restart: {}", e);
+        }
+        if let Some(ref e) = status.error {
+            utils::print_with_color(format!("  {}\n", e).as_str(), 31, false);
+        }
+    }
+}
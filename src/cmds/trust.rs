@@ -0,0 +1,56 @@
+#![allow(clippy::new_without_default)]
+
+use crate::cfg::Config;
+use crate::cmds::SubCmd;
+use crate::known_hosts;
+use crate::utils;
+use anyhow::Result;
+use clap::{Arg, ArgMatches, Command};
+
+pub struct Trust {}
+
+impl SubCmd for Trust {
+    fn usage<'a>() -> Command<'a> {
+        Command::new("trust")
+            .about("Fetch and record the host keys of a tunnel into the known_hosts file of sshp")
+            .arg(
+                Arg::new("tunnel")
+                    .help("tunnel name, `dynamic_proxy` or `multi_proxy` for the sections")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("config")
+                    .help("config file path")
+                    .short('c')
+                    .required(false)
+                    .default_value("~/.config/sshp.toml"),
+            )
+    }
+
+    fn handler(&self, arg: &ArgMatches) -> Result<()> {
+        let mut config = Config::loads(arg.value_of("config"))?;
+        config.select_tunnel(arg.value_of("tunnel").unwrap())?;
+        let file = config.get_known_hosts_file();
+        for host in known_hosts::get_hosts(&config)? {
+            let fingerprints = known_hosts::trust(&file, &host)?;
+            utils::print_with_color("Trust ", 32, false);
+            utils::print_with_color(host.name().as_str(), 37, true);
+            println!();
+            for e in fingerprints {
+                println!("  {}", e);
+            }
+        }
+        utils::print_with_color(
+            format!("Recorded into {}\n", file.to_string_lossy()).as_str(),
+            34,
+            false,
+        );
+        Ok(())
+    }
+}
+
+impl Trust {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
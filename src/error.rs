@@ -0,0 +1,167 @@
+/// exit codes of sshp, other errors exit with 1
+pub const EXIT_FAILED: i32 = 1;
+pub const EXIT_CONFIG_INVALID: i32 = 10;
+pub const EXIT_ADDRESS_IN_USE: i32 = 11;
+pub const EXIT_AUTH_FAILED: i32 = 12;
+pub const EXIT_HOST_UNREACHABLE: i32 = 13;
+pub const EXIT_HOST_KEY_MISMATCH: i32 = 14;
+pub const EXIT_HEALTH_CHECK_FAILED: i32 = 15;
+pub const EXIT_FORK_FAILED: i32 = 16;
+pub const EXIT_HOST_KEY_UNKNOWN: i32 = 17;
+
+/// help of the exit codes
+pub const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0     success
+    1     other errors
+    10    config invalid
+    11    address already in use
+    12    ssh authentication failed
+    13    remote host unreachable
+    14    host key mismatch
+    15    health check failed
+    16    fork failed
+    17    host key not trusted yet, run `sshp trust`";
+
+/// errors scripts can tell apart by the exit code
+#[derive(Debug, PartialEq)]
+pub enum SshpError {
+    ConfigInvalid(String),
+    /// a local listen addr, or the remote port of a remote forward, is in use
+    AddressInUse(String),
+    AuthFailed(String),
+    HostUnreachable(String),
+    HostKeyMismatch(String),
+    /// the tunnel started but the check through it failed
+    HealthCheckFailed(String),
+    ForkFailed(String),
+    /// the host is not in the known_hosts file under `host_key_policy = "strict"`
+    HostKeyUnknown(String),
+}
+
+impl std::fmt::Display for SshpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SshpError::ConfigInvalid(e)
+            | SshpError::AddressInUse(e)
+            | SshpError::AuthFailed(e)
+            | SshpError::HostUnreachable(e)
+            | SshpError::HostKeyMismatch(e)
+            | SshpError::HealthCheckFailed(e)
+            | SshpError::ForkFailed(e)
+            | SshpError::HostKeyUnknown(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SshpError {}
+
+impl SshpError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SshpError::ConfigInvalid(_) => EXIT_CONFIG_INVALID,
+            SshpError::AddressInUse(_) => EXIT_ADDRESS_IN_USE,
+            SshpError::AuthFailed(_) => EXIT_AUTH_FAILED,
+            SshpError::HostUnreachable(_) => EXIT_HOST_UNREACHABLE,
+            SshpError::HostKeyMismatch(_) => EXIT_HOST_KEY_MISMATCH,
+            SshpError::HealthCheckFailed(_) => EXIT_HEALTH_CHECK_FAILED,
+            SshpError::ForkFailed(_) => EXIT_FORK_FAILED,
+            SshpError::HostKeyUnknown(_) => EXIT_HOST_KEY_UNKNOWN,
+        }
+    }
+}
+
+/// the variant of a typed error
+type Kind = fn(String) -> SshpError;
+
+/// patterns of ssh stderr, checked in order
+const SSH_ERRORS: [(&str, Kind); 17] = [
+    (
+        "REMOTE HOST IDENTIFICATION HAS CHANGED",
+        SshpError::HostKeyMismatch,
+    ),
+    // strict checking of a host not recorded yet, after the changed key above
+    (
+        "you have requested strict checking",
+        SshpError::HostKeyUnknown,
+    ),
+    ("Host key verification failed", SshpError::HostKeyUnknown),
+    ("Permission denied", SshpError::AuthFailed),
+    ("Too many authentication failures", SshpError::AuthFailed),
+    ("Authentication failed", SshpError::AuthFailed),
+    ("Address already in use", SshpError::AddressInUse),
+    ("cannot listen to port", SshpError::AddressInUse),
+    ("remote port forwarding failed", SshpError::AddressInUse),
+    ("Could not resolve hostname", SshpError::HostUnreachable),
+    ("Name or service not known", SshpError::HostUnreachable),
+    ("Connection refused", SshpError::HostUnreachable),
+    ("Connection timed out", SshpError::HostUnreachable),
+    ("Operation timed out", SshpError::HostUnreachable),
+    ("No route to host", SshpError::HostUnreachable),
+    ("Network is unreachable", SshpError::HostUnreachable),
+    ("Connection closed by", SshpError::HostUnreachable),
+];
+
+/// the error of a failed ssh with `msg`, typed by the first known pattern in its stderr
+pub fn classify_ssh_error(stderr: &str, msg: String) -> anyhow::Error {
+    match SSH_ERRORS.iter().find(|e| stderr.contains(e.0)) {
+        Some((_, error)) => error(msg).into(),
+        None => anyhow::anyhow!(msg),
+    }
+}
+
+/// the error of a failed bind with `msg`, typed if the addr is in use
+pub fn classify_bind_error(e: &std::io::Error, msg: String) -> anyhow::Error {
+    match e.kind() {
+        std::io::ErrorKind::AddrInUse => SshpError::AddressInUse(msg).into(),
+        _ => anyhow::anyhow!(msg),
+    }
+}
+
+/// the exit code of the first typed error in the chain of e
+pub fn get_exit_code(e: &anyhow::Error) -> i32 {
+    e.chain()
+        .find_map(|e| e.downcast_ref::<SshpError>())
+        .map(|e| e.exit_code())
+        .unwrap_or(EXIT_FAILED)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::{self, SshpError};
+
+    #[test]
+    fn test_classify_ssh_error() {
+        let e = error::classify_ssh_error(
+            "root@10.0.0.1: Permission denied (publickey,password).\n",
+            "Open dynamic proxy failed".to_string(),
+        );
+        assert_eq!(
+            e.downcast_ref::<SshpError>(),
+            Some(&SshpError::AuthFailed(
+                "Open dynamic proxy failed".to_string()
+            ))
+        );
+        assert_eq!(error::get_exit_code(&e), error::EXIT_AUTH_FAILED);
+        let e = error::classify_ssh_error(
+            "bind [127.0.0.1]:50001: Address already in use\n",
+            String::new(),
+        );
+        assert_eq!(error::get_exit_code(&e), error::EXIT_ADDRESS_IN_USE);
+        let e = error::classify_ssh_error(
+            "@@@@@\n@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @\nHost key verification failed.\n",
+            String::new(),
+        );
+        assert_eq!(error::get_exit_code(&e), error::EXIT_HOST_KEY_MISMATCH);
+        let e = error::classify_ssh_error(
+            "No ED25519 host key is known for 10.0.0.1 and you have requested strict checking.\nHost key verification failed.\n",
+            String::new(),
+        );
+        assert_eq!(error::get_exit_code(&e), error::EXIT_HOST_KEY_UNKNOWN);
+        let e = error::classify_ssh_error("something else\n", String::new());
+        assert_eq!(error::get_exit_code(&e), error::EXIT_FAILED);
+        assert_eq!(
+            error::get_exit_code(&e.context("start failed")),
+            error::EXIT_FAILED
+        );
+    }
+}
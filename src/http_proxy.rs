@@ -0,0 +1,215 @@
+use crate::socks;
+use crate::utils;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// max size of the request line and headers
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// headers only meaningful between the client and the proxy
+const HOP_HEADERS: [&str; 4] = [
+    "proxy-connection",
+    "proxy-authorization",
+    "connection",
+    "keep-alive",
+];
+
+/// a request read from the client, `body` is what was read after the headers
+struct Request {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+/// serve http CONNECT and absolute-URI requests on listener, forwarding them through
+/// the socks5 proxy listening on `socks_addr`
+pub fn serve(listener: TcpListener, socks_addr: String, log_path: std::path::PathBuf) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let socks_addr = socks_addr.clone();
+                std::thread::spawn(move || handle(stream, socks_addr.as_str()));
+            }
+            Err(e) => utils::write_log(
+                &log_path,
+                format!("http proxy accept error, {}", e).as_str(),
+            ),
+        }
+    }
+}
+
+fn handle(mut client: TcpStream, socks_addr: &str) {
+    let request = match read_request(&mut client) {
+        Ok(e) => e,
+        Err(e) => {
+            respond(&mut client, "400 Bad Request", e.as_str());
+            return;
+        }
+    };
+    let (host, port, path) = match parse_target(request.method.as_str(), request.target.as_str()) {
+        Ok(e) => e,
+        Err(e) => {
+            respond(&mut client, "400 Bad Request", e.as_str());
+            return;
+        }
+    };
+    let mut upstream =
+        match socks::connect(socks_addr, host.as_str(), port, Duration::from_secs(10)) {
+            Ok(e) => e,
+            Err(e) => {
+                respond(&mut client, "502 Bad Gateway", e.to_string().as_str());
+                return;
+            }
+        };
+    let res = if request.method == "CONNECT" {
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+    } else {
+        // one request per connection, the origin closes after the response
+        let mut head = format!("{} {} {}\r\n", request.method, path, request.version);
+        if !request
+            .headers
+            .iter()
+            .any(|(k, _)| k.eq_ignore_ascii_case("host"))
+        {
+            head += format!("Host: {}\r\n", request.target_authority()).as_str();
+        }
+        for (k, v) in request.headers.iter() {
+            if !HOP_HEADERS.contains(&k.to_ascii_lowercase().as_str()) {
+                head += format!("{}: {}\r\n", k, v).as_str();
+            }
+        }
+        head += "Connection: close\r\n\r\n";
+        upstream
+            .write_all(head.as_bytes())
+            .and_then(|_| upstream.write_all(&request.body))
+    };
+    if res.is_ok() {
+        utils::relay(client, upstream);
+    }
+}
+
+impl Request {
+    /// `host[:port]` of an absolute-URI target
+    fn target_authority(&self) -> &str {
+        let rest = self
+            .target
+            .split_once("://")
+            .map(|e| e.1)
+            .unwrap_or_default();
+        rest.split(['/', '?']).next().unwrap_or_default()
+    }
+}
+
+/// read the request line and headers
+fn read_request(stream: &mut TcpStream) -> Result<Request, String> {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .map_err(|e| e.to_string())?;
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let end = loop {
+        if let Some(e) = buf.windows(4).position(|e| e == b"\r\n\r\n") {
+            break e;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err("request headers too large".to_string());
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) => return Err("connection closed before the request ends".to_string()),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) => return Err(e.to_string()),
+        }
+    };
+    stream.set_read_timeout(None).map_err(|e| e.to_string())?;
+    let head = String::from_utf8_lossy(&buf[..end]).to_string();
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let parts = request_line.split(' ').collect::<Vec<&str>>();
+    if parts.len() != 3 {
+        return Err(format!("invalid request line `{}`", request_line));
+    }
+    let mut headers = Vec::new();
+    for line in lines {
+        match line.split_once(':') {
+            Some((k, v)) => headers.push((k.trim().to_string(), v.trim().to_string())),
+            None => return Err(format!("invalid header `{}`", line)),
+        }
+    }
+    Ok(Request {
+        method: parts[0].to_string(),
+        target: parts[1].to_string(),
+        version: parts[2].to_string(),
+        headers,
+        body: buf[end + 4..].to_vec(),
+    })
+}
+
+/// host, port and origin-form path of the request target, `host:port` for CONNECT
+/// and `http://host[:port]/path` for the others
+fn parse_target(method: &str, target: &str) -> Result<(String, u16, String), String> {
+    let (authority, default_port, path) = if method == "CONNECT" {
+        (target, None, String::new())
+    } else {
+        let rest = match target.split_once("://") {
+            Some(("http", rest)) => rest,
+            _ => return Err(format!("need an absolute http uri, got `{}`", target)),
+        };
+        match rest.find(['/', '?']) {
+            Some(e) if rest[e..].starts_with('/') => (&rest[..e], Some(80), rest[e..].to_string()),
+            Some(e) => (&rest[..e], Some(80), format!("/{}", &rest[e..])),
+            None => (rest, Some(80), "/".to_string()),
+        }
+    };
+    // `[::1]:443` or `host:443`, a bare ipv6 has no port
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if host.starts_with('[') || !host.contains(':') => {
+            (host, Some(port.parse::<u16>().map_err(|e| e.to_string())?))
+        }
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match port {
+        Some(port) if !host.is_empty() => Ok((host.to_string(), port, path)),
+        _ => Err(format!("invalid request target `{}`", target)),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, msg: &str) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        msg.len(),
+        msg
+    );
+    stream.write_all(response.as_bytes()).ok();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::http_proxy::parse_target;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            parse_target("CONNECT", "www.google.com:443"),
+            Ok(("www.google.com".to_string(), 443, String::new()))
+        );
+        assert_eq!(
+            parse_target("CONNECT", "[2001:db8::1]:443"),
+            Ok(("2001:db8::1".to_string(), 443, String::new()))
+        );
+        assert_eq!(
+            parse_target("GET", "http://example.com:8080/a?b=1"),
+            Ok(("example.com".to_string(), 8080, "/a?b=1".to_string()))
+        );
+        assert_eq!(
+            parse_target("GET", "http://example.com?b=1"),
+            Ok(("example.com".to_string(), 80, "/?b=1".to_string()))
+        );
+        assert!(parse_target("GET", "/index.html").is_err());
+        assert!(parse_target("CONNECT", "www.google.com").is_err());
+    }
+}
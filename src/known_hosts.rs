@@ -0,0 +1,241 @@
+use crate::cfg::{Config, HostKeyPolicy};
+use crate::error::SshpError;
+use anyhow::Result;
+use std::io::Write;
+use std::path::Path;
+
+/// a host reached by ssh whose key is checked
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnownHost {
+    pub host: String,
+    pub port: usize,
+    /// pinned `SHA256:...` fingerprint
+    pub fingerprint: Option<String>,
+}
+
+impl KnownHost {
+    pub fn name(&self) -> String {
+        host_name(self.host.as_str(), self.port)
+    }
+}
+
+/// the name of a host in known_hosts, `[host]:port` if port is not 22
+pub fn host_name(host: &str, port: usize) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// resolve the hostname and port of a Host alias by `ssh -G`
+fn resolve_alias(config: &Config, alias: &str) -> Result<(String, usize)> {
+    let mut args = Vec::new();
+    let extra = config.get_ssh_extra_args();
+    if let Some(i) = extra.iter().position(|e| e == "-F") {
+        args.extend_from_slice(&extra[i..i + 2]);
+    }
+    args.extend(vec!["-G".to_string(), alias.to_string()]);
+    let output = std::process::Command::new("ssh").args(args).output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Resolve ssh config host {} failed, {}",
+            alias,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let (mut host, mut port) = (alias.to_string(), 22);
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        match line.split_once(' ') {
+            Some(("hostname", e)) => host = e.to_string(),
+            Some(("port", e)) => port = e.parse()?,
+            _ => {}
+        }
+    }
+    Ok((host, port))
+}
+
+/// the hosts the selected tunnel connects to, in order
+pub fn get_hosts(config: &Config) -> Result<Vec<KnownHost>> {
+    let fingerprint = config.get_host_key_fingerprint().map(|e| e.to_string());
+    if config.get_tunnel_type() == "multi" {
+        let mut hosts = Vec::new();
+        for (i, hop) in config.get_multi_dynamic_hops().into_iter().enumerate() {
+            let (host, port) = match config.get_ssh_config_host() {
+                Some(alias) if i == 0 => resolve_alias(config, alias)?,
+                _ => (hop.ip, hop.port),
+            };
+            hosts.push(KnownHost {
+                host,
+                port,
+                fingerprint: match hop.host_key_fingerprint {
+                    Some(e) => Some(e),
+                    None if i == 0 => fingerprint.clone(),
+                    None => None,
+                },
+            });
+        }
+        return Ok(hosts);
+    }
+    if let Some(alias) = config.get_ssh_config_host() {
+        let (host, port) = resolve_alias(config, alias)?;
+        return Ok(vec![KnownHost {
+            host,
+            port,
+            fingerprint,
+        }]);
+    }
+    if matches!(config.get_tunnel_type(), "local" | "remote") {
+        return Ok(vec![KnownHost {
+            host: config.get_forward_remote_ip().to_string(),
+            port: config.get_forward_remote_port(),
+            fingerprint,
+        }]);
+    }
+    // the fingerprint pins every remote host, equivalent bastions share the key
+    Ok(config
+        .get_dynamic_remote_hosts()?
+        .into_iter()
+        .map(|e| KnownHost {
+            host: e.ip,
+            port: e.port,
+            fingerprint: fingerprint.clone(),
+        })
+        .collect())
+}
+
+/// `SHA256:...` fingerprint of every key in known_hosts format
+fn fingerprints(keys: &str) -> Result<Vec<(String, String)>> {
+    let mut res = Vec::new();
+    for key in keys.lines() {
+        if key.trim().is_empty() || key.starts_with('#') {
+            continue;
+        }
+        let mut child = std::process::Command::new("ssh-keygen")
+            .args(vec!["-l", "-f", "-"])
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(format!("{}\n", key).as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        // `256 SHA256:... host (ED25519)`
+        if let Some(fingerprint) = String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .nth(1)
+        {
+            res.push((fingerprint.to_string(), key.to_string()));
+        }
+    }
+    Ok(res)
+}
+
+/// fingerprints of the keys recorded for host in the known_hosts file
+fn recorded_fingerprints(file: &Path, host: &KnownHost) -> Result<Vec<String>> {
+    if !file.exists() {
+        return Ok(Vec::new());
+    }
+    let output = std::process::Command::new("ssh-keygen")
+        .arg("-F")
+        .arg(host.name())
+        .arg("-f")
+        .arg(file)
+        .output()?;
+    let keys = String::from_utf8_lossy(&output.stdout).to_string();
+    Ok(fingerprints(keys.as_str())?
+        .into_iter()
+        .map(|e| e.0)
+        .collect())
+}
+
+/// fetch the keys of host by ssh-keyscan and record them into the known_hosts file,
+/// only the key matching the pinned fingerprint is recorded if any
+pub fn trust(file: &Path, host: &KnownHost) -> Result<Vec<String>> {
+    let output = std::process::Command::new("ssh-keyscan")
+        .args(vec![
+            "-p",
+            host.port.to_string().as_str(),
+            host.host.as_str(),
+        ])
+        .stderr(std::process::Stdio::null())
+        .output()?;
+    let keys = String::from_utf8_lossy(&output.stdout).to_string();
+    let mut keys = fingerprints(keys.as_str())?;
+    if keys.is_empty() {
+        return Err(SshpError::HostUnreachable(format!(
+            "Cannot fetch the host key of {}",
+            host.name()
+        ))
+        .into());
+    }
+    if let Some(ref pinned) = host.fingerprint {
+        keys.retain(|e| &e.0 == pinned);
+        if keys.is_empty() {
+            return Err(SshpError::HostKeyMismatch(format!(
+                "Host key mismatch, no key of {} matches the fingerprint {}",
+                host.name(),
+                pinned
+            ))
+            .into());
+        }
+    }
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    if file.exists() {
+        std::process::Command::new("ssh-keygen")
+            .arg("-R")
+            .arg(host.name())
+            .arg("-f")
+            .arg(file)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()?;
+    }
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)?;
+    for (_, key) in keys.iter() {
+        f.write_all(format!("{}\n", key).as_bytes())?;
+    }
+    Ok(keys.into_iter().map(|e| e.0).collect())
+}
+
+/// create the dir of the known_hosts file and make sure the pinned hosts the selected
+/// tunnel connects through its `upstream`th remote host are recorded with the pinned key,
+/// hosts not recorded yet are trusted first
+pub fn prepare(config: &Config, upstream: usize) -> Result<()> {
+    if config.get_host_key_policy()? == HostKeyPolicy::Off {
+        return Ok(());
+    }
+    let file = config.get_known_hosts_file();
+    if let Some(dir) = file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut hosts = get_hosts(config)?;
+    // dynamic tunnels connect one of the remote hosts, the others are prepared on failover
+    if config.get_tunnel_type() == "dynamic" && hosts.len() > 1 {
+        hosts = vec![hosts.remove(upstream.min(hosts.len() - 1))];
+    }
+    for host in hosts {
+        let pinned = match host.fingerprint {
+            Some(ref e) => e,
+            None => continue,
+        };
+        let recorded = recorded_fingerprints(&file, &host)?;
+        if recorded.is_empty() {
+            trust(&file, &host)?;
+        } else if !recorded.iter().any(|e| e == pinned) {
+            return Err(SshpError::HostKeyMismatch(format!(
+                "Host key mismatch, the recorded key of {} does not match the fingerprint {}",
+                host.name(),
+                pinned
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
@@ -1,5 +1,13 @@
+pub mod backoff;
 pub mod cfg;
 pub mod cmds;
+pub mod error;
+pub mod http_proxy;
+pub mod known_hosts;
+pub mod pac;
+pub mod router;
+pub mod socks;
+pub mod state;
 pub mod utils;
 use clap::Command;
 use cmds::SubCmd;
@@ -8,9 +16,16 @@ fn main() {
     let m = Command::new("sshp")
         .about("A CLI to Support SSH Dynamic Proxy.")
         .version("0.1.2")
+        .after_help(error::EXIT_CODES_HELP)
         .subcommands(vec![
             cmds::dynamic_proxy::DynamicProxy::usage().display_order(1),
             cmds::multi_proxy::MultiDynamicProxy::usage().display_order(2),
+            cmds::forward::Forward::usage().display_order(3),
+            cmds::status::Status::usage().display_order(4),
+            cmds::trust::Trust::usage().display_order(5),
+            cmds::pac::Pac::usage().display_order(6),
+            cmds::router::Router::usage().display_order(7),
+            cmds::config::ConfigCmd::usage().display_order(8),
         ])
         .arg_required_else_help(true)
         .get_matches();
@@ -18,13 +33,49 @@ fn main() {
         Some(("dynamic_proxy", args)) => {
             if let Err(e) = cmds::dynamic_proxy::DynamicProxy::new().handler(args) {
                 utils::print_with_color((e.to_string() + "\n").as_str(), 31, true);
-                std::process::exit(1);
+                std::process::exit(error::get_exit_code(&e));
             }
         }
         Some(("multi_proxy", args)) => {
             if let Err(e) = cmds::multi_proxy::MultiDynamicProxy::new().handler(args) {
                 utils::print_with_color((e.to_string() + "\n").as_str(), 31, true);
-                std::process::exit(1);
+                std::process::exit(error::get_exit_code(&e));
+            }
+        }
+        Some(("forward", args)) => {
+            if let Err(e) = cmds::forward::Forward::new().handler(args) {
+                utils::print_with_color((e.to_string() + "\n").as_str(), 31, true);
+                std::process::exit(error::get_exit_code(&e));
+            }
+        }
+        Some(("status", args)) => {
+            if let Err(e) = cmds::status::Status::new().handler(args) {
+                utils::print_with_color((e.to_string() + "\n").as_str(), 31, true);
+                std::process::exit(error::get_exit_code(&e));
+            }
+        }
+        Some(("trust", args)) => {
+            if let Err(e) = cmds::trust::Trust::new().handler(args) {
+                utils::print_with_color((e.to_string() + "\n").as_str(), 31, true);
+                std::process::exit(error::get_exit_code(&e));
+            }
+        }
+        Some(("pac", args)) => {
+            if let Err(e) = cmds::pac::Pac::new().handler(args) {
+                utils::print_with_color((e.to_string() + "\n").as_str(), 31, true);
+                std::process::exit(error::get_exit_code(&e));
+            }
+        }
+        Some(("router", args)) => {
+            if let Err(e) = cmds::router::Router::new().handler(args) {
+                utils::print_with_color((e.to_string() + "\n").as_str(), 31, true);
+                std::process::exit(error::get_exit_code(&e));
+            }
+        }
+        Some(("config", args)) => {
+            if let Err(e) = cmds::config::ConfigCmd::new().handler(args) {
+                utils::print_with_color((e.to_string() + "\n").as_str(), 31, true);
+                std::process::exit(error::get_exit_code(&e));
             }
         }
         _ => {}
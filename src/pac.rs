@@ -0,0 +1,151 @@
+use crate::cfg::{Cidr, Config, Rule};
+use crate::utils;
+use anyhow::Result;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+
+/// the pac file routing hosts by the rules in `[pac]`, all the conditions of a rule must match
+pub fn generate(config: &Config) -> Result<String> {
+    let mut body = String::from("function FindProxyForURL(url, host) {\n");
+    for rule in config.get_pac_rules()? {
+        body += format!(
+            "    if ({}) return \"{}\";\n",
+            get_condition(&rule),
+            get_proxy(config, rule.tunnel.as_deref())?
+        )
+        .as_str();
+    }
+    body += format!(
+        "    return \"{}\";\n}}\n",
+        get_proxy(config, config.get_pac_default()?.as_deref())?
+    )
+    .as_str();
+    Ok(body)
+}
+
+fn get_condition(rule: &Rule) -> String {
+    let mut conditions = Vec::new();
+    if let Some(ref e) = rule.domain {
+        conditions.push(format!(
+            "host == \"{}\" || dnsDomainIs(host, \".{}\")",
+            e, e
+        ));
+    }
+    if let Some(ref e) = rule.cidr {
+        conditions.push(get_cidr_condition(e));
+    }
+    if conditions.len() == 1 {
+        return conditions.remove(0);
+    }
+    conditions
+        .iter()
+        .map(|e| format!("({})", e))
+        .collect::<Vec<String>>()
+        .join(" && ")
+}
+
+/// `isInNet` only knows ipv4 masks, ipv6 ranges use `isInNetEx` of chrome and firefox
+fn get_cidr_condition(cidr: &Cidr) -> String {
+    match cidr.ip {
+        IpAddr::V4(ip) => {
+            let mask = match cidr.prefix {
+                0 => 0,
+                e => u32::MAX << (32 - e as u32),
+            };
+            format!(
+                "isInNet(host, \"{}\", \"{}\")",
+                ip,
+                std::net::Ipv4Addr::from(mask)
+            )
+        }
+        IpAddr::V6(ip) => format!(
+            "(typeof isInNetEx == \"function\" && isInNetEx(host, \"{}/{}\"))",
+            ip, cidr.prefix
+        ),
+    }
+}
+
+/// `SOCKS5` with `SOCKS` as the fallback for old browsers
+fn get_proxy(config: &Config, tunnel: Option<&str>) -> Result<String> {
+    match tunnel {
+        Some(e) => {
+            let addr = config.get_tunnel_local_addr(e)?;
+            Ok(format!("SOCKS5 {}; SOCKS {}", addr, addr))
+        }
+        None => Ok("DIRECT".to_string()),
+    }
+}
+
+/// serve `pac` at `/proxy.pac` on listener
+pub fn serve(listener: TcpListener, pac: String, log_path: std::path::PathBuf) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                if let Err(e) = respond(&mut stream, pac.as_str()) {
+                    utils::write_log(&log_path, format!("serve pac error, {}", e).as_str());
+                }
+            }
+            Err(e) => utils::write_log(&log_path, format!("pac accept error, {}", e).as_str()),
+        }
+    }
+}
+
+fn respond(stream: &mut TcpStream, pac: &str) -> Result<()> {
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|e| e == b"\r\n\r\n") && buf.len() < 8192 {
+        match stream.read(&mut chunk)? {
+            0 => break,
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let head = String::from_utf8_lossy(&buf).to_string();
+    let path = head.split(' ').nth(1).unwrap_or_default();
+    let (status, content_type, body) = match path.split('?').next() {
+        Some("/proxy.pac") => ("200 OK", "application/x-ns-proxy-autoconfig", pac),
+        _ => ("404 Not Found", "text/plain", "not found"),
+    };
+    stream.write_all(
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )
+        .as_bytes(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::Config;
+    use crate::pac;
+
+    #[test]
+    fn test_generate() {
+        let config: Config = toml::from_str(
+            r#"
+            [dynamic_proxy]
+            local_addr = "localhost:50001"
+            remote_ip = "192.168.8.22"
+
+            [pac]
+            rules = [
+                { domain = "corp.example.com", tunnel = "dynamic_proxy" },
+                { cidr = "10.0.0.0/8", tunnel = "dynamic_proxy" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let pac = pac::generate(&config).unwrap();
+        assert!(pac.contains(
+            "if (host == \"corp.example.com\" || dnsDomainIs(host, \".corp.example.com\")) \
+             return \"SOCKS5 localhost:50001; SOCKS localhost:50001\";"
+        ));
+        assert!(pac.contains("if (isInNet(host, \"10.0.0.0\", \"255.0.0.0\"))"));
+        assert!(pac.contains("return \"DIRECT\";\n}"));
+    }
+}
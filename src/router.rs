@@ -0,0 +1,203 @@
+use crate::cfg::{Config, Rule};
+use crate::socks;
+use crate::utils;
+use anyhow::Result;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// socks5 reply codes
+const REP_SUCCEEDED: u8 = 0;
+const REP_HOST_UNREACHABLE: u8 = 4;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// routes of the router resolved from the config, the tunnels are None for direct
+#[derive(Debug, Clone)]
+pub struct Router {
+    /// rules with the socks5 addrs of their tunnels
+    rules: Vec<(Rule, Option<String>)>,
+    /// name and socks5 addr of the default tunnel
+    default: Option<(String, String)>,
+}
+
+impl Router {
+    pub fn new(config: &Config) -> Result<Self> {
+        let get_addr = |tunnel: Option<&str>| -> Result<Option<String>> {
+            match tunnel {
+                Some(e) => Ok(Some(config.get_tunnel_local_addr(e)?.to_string())),
+                None => Ok(None),
+            }
+        };
+        let mut rules = Vec::new();
+        for rule in config.get_router_rules()? {
+            let addr = get_addr(rule.tunnel.as_deref())?;
+            rules.push((rule, addr));
+        }
+        let default = match config.get_router_default()? {
+            Some(name) => {
+                let addr = config.get_tunnel_local_addr(name.as_str())?.to_string();
+                Some((name, addr))
+            }
+            None => None,
+        };
+        Ok(Router { rules, default })
+    }
+
+    /// name and socks5 addr of the tunnel of the target, None for direct
+    pub fn route(&self, host: &str, port: u16) -> Option<(&str, &str)> {
+        for (rule, addr) in self.rules.iter() {
+            if rule.matches(host, port) {
+                return rule.tunnel.as_deref().zip(addr.as_deref());
+            }
+        }
+        self.default
+            .as_ref()
+            .map(|(name, addr)| (name.as_str(), addr.as_str()))
+    }
+}
+
+/// serve socks5 CONNECT on listener, connecting each target directly or through the
+/// tunnel of the first matched rule
+pub fn serve(listener: TcpListener, router: Router, log_path: std::path::PathBuf) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let router = router.clone();
+                let log_path = log_path.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = handle(stream, &router) {
+                        utils::write_log(&log_path, format!("router {}", e).as_str());
+                    }
+                });
+            }
+            Err(e) => utils::write_log(&log_path, format!("router accept error, {}", e).as_str()),
+        }
+    }
+}
+
+fn handle(mut client: TcpStream, router: &Router) -> Result<()> {
+    client.set_read_timeout(Some(Duration::from_secs(30)))?;
+    // greeting, only no authentication is supported
+    let mut head = [0u8; 2];
+    client.read_exact(&mut head)?;
+    let mut methods = vec![0u8; head[1] as usize];
+    client.read_exact(&mut methods)?;
+    if head[0] != 5 || !methods.contains(&0) {
+        client.write_all(&[5, 0xff])?;
+        anyhow::bail!("unsupported socks greeting {:?}", head);
+    }
+    client.write_all(&[5, 0])?;
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request)?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip)?;
+            IpAddr::from(ip).to_string()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip)?;
+            IpAddr::from(ip).to_string()
+        }
+        3 => {
+            let mut len = [0u8; 1];
+            client.read_exact(&mut len)?;
+            let mut host = vec![0u8; len[0] as usize];
+            client.read_exact(&mut host)?;
+            String::from_utf8_lossy(&host).to_string()
+        }
+        e => {
+            reply(&mut client, REP_ADDRESS_TYPE_NOT_SUPPORTED)?;
+            anyhow::bail!("unsupported address type {}", e);
+        }
+    };
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port)?;
+    let port = u16::from_be_bytes(port);
+    if request[1] != 1 {
+        reply(&mut client, REP_COMMAND_NOT_SUPPORTED)?;
+        anyhow::bail!("unsupported command {} to {}:{}", request[1], host, port);
+    }
+    client.set_read_timeout(None)?;
+    let timeout = Duration::from_secs(10);
+    let upstream = match router.route(host.as_str(), port) {
+        Some((tunnel, addr)) => socks::connect(addr, host.as_str(), port, timeout)
+            .map_err(|e| anyhow::anyhow!("connect {}:{} via {} error, {}", host, port, tunnel, e)),
+        None => connect_direct(host.as_str(), port, timeout)
+            .map_err(|e| anyhow::anyhow!("connect {}:{} directly error, {}", host, port, e)),
+    };
+    match upstream {
+        Ok(upstream) => {
+            reply(&mut client, REP_SUCCEEDED)?;
+            utils::relay(client, upstream);
+            Ok(())
+        }
+        Err(e) => {
+            reply(&mut client, REP_HOST_UNREACHABLE).ok();
+            Err(e)
+        }
+    }
+}
+
+fn connect_direct(host: &str, port: u16, timeout: Duration) -> Result<TcpStream> {
+    let mut last_error = format!("cannot resolve {}", host);
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(e) => return Ok(e),
+            Err(e) => last_error = e.to_string(),
+        }
+    }
+    anyhow::bail!(last_error)
+}
+
+/// reply with the bound addr 0.0.0.0:0, clients do not use it for CONNECT
+fn reply(client: &mut TcpStream, rep: u8) -> std::io::Result<()> {
+    client.write_all(&[5, rep, 0, 1, 0, 0, 0, 0, 0, 0])
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cfg::Config;
+    use crate::router::Router;
+
+    #[test]
+    fn test_route() {
+        let config: Config = toml::from_str(
+            r#"
+            [dynamic_proxy]
+            local_addr = "localhost:50001"
+            remote_ip = "192.168.8.22"
+
+            [tunnels.staging]
+            type = "dynamic"
+            local_addr = "localhost:50011"
+            remote_ip = "10.0.0.1"
+
+            [router]
+            default = "dynamic_proxy"
+            rules = [
+                { domain = "corp.example.com", tunnel = "staging" },
+                { cidr = "10.0.0.0/8", port = 22, tunnel = "staging" },
+                { domain = "example.com", tunnel = "direct" },
+            ]
+            "#,
+        )
+        .unwrap();
+        let router = Router::new(&config).unwrap();
+        assert_eq!(
+            router.route("git.corp.example.com", 443),
+            Some(("staging", "localhost:50011"))
+        );
+        assert_eq!(
+            router.route("10.1.1.1", 22),
+            Some(("staging", "localhost:50011"))
+        );
+        assert_eq!(
+            router.route("10.1.1.1", 80),
+            Some(("dynamic_proxy", "localhost:50001"))
+        );
+        assert_eq!(router.route("www.example.com", 443), None);
+    }
+}
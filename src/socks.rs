@@ -0,0 +1,231 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// why a connection through a socks5 proxy failed
+#[derive(Debug, PartialEq)]
+pub enum SocksError {
+    /// nothing accepts connections on the proxy addr
+    Unreachable(String),
+    /// the listener does not speak socks5 or refused the handshake
+    Refused(String),
+    /// the listener accepts but does not answer in time, like a hung ssh
+    Timeout(String),
+    /// the proxy could not connect to the target
+    UpstreamFailed(String),
+}
+
+impl std::fmt::Display for SocksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SocksError::Unreachable(e) => write!(f, "socks5 listener unreachable, {}", e),
+            SocksError::Refused(e) => write!(f, "socks5 handshake refused, {}", e),
+            SocksError::Timeout(e) => write!(f, "socks5 handshake timed out, {}", e),
+            SocksError::UpstreamFailed(e) => write!(f, "upstream connect failed, {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SocksError {}
+
+fn reply_message(rep: u8) -> &'static str {
+    match rep {
+        1 => "general socks server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// the error of a failed read or write of the handshake
+fn handshake_error(e: std::io::Error) -> SocksError {
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => {
+            SocksError::Timeout(e.to_string())
+        }
+        _ => SocksError::Refused(e.to_string()),
+    }
+}
+
+/// connect the socks5 proxy listening on `proxy` and finish the greeting
+fn handshake(proxy: &str, timeout: Duration) -> Result<TcpStream, SocksError> {
+    let addrs = proxy
+        .to_socket_addrs()
+        .map_err(|e| SocksError::Unreachable(e.to_string()))?;
+    let mut last_error = format!("cannot resolve {}", proxy);
+    let mut stream = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(e) => {
+                stream = Some(e);
+                break;
+            }
+            Err(e) => last_error = e.to_string(),
+        }
+    }
+    let mut stream = stream.ok_or(SocksError::Unreachable(last_error))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(handshake_error)?;
+    stream
+        .set_write_timeout(Some(timeout))
+        .map_err(handshake_error)?;
+    // greeting with no authentication
+    stream.write_all(&[5, 1, 0]).map_err(handshake_error)?;
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf).map_err(handshake_error)?;
+    if buf != [5, 0] {
+        return Err(SocksError::Refused(format!(
+            "unexpected greeting reply {:?}",
+            buf
+        )));
+    }
+    Ok(stream)
+}
+
+/// open a connection to `host:port` through the socks5 proxy listening on `proxy`
+pub fn connect(
+    proxy: &str,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<TcpStream, SocksError> {
+    let mut stream = handshake(proxy, timeout)?;
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(SocksError::Refused(format!("host {} too long", host)));
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).map_err(handshake_error)?;
+    let upstream_failed = |e: std::io::Error| SocksError::UpstreamFailed(e.to_string());
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).map_err(upstream_failed)?;
+    if head[0] != 5 {
+        return Err(SocksError::Refused(format!(
+            "unexpected reply version {}",
+            head[0]
+        )));
+    }
+    if head[1] != 0 {
+        return Err(SocksError::UpstreamFailed(format!(
+            "{}:{}, {}",
+            host,
+            port,
+            reply_message(head[1])
+        )));
+    }
+    // skip the bound address
+    let len = match head[3] {
+        1 => 4 + 2,
+        4 => 16 + 2,
+        3 => {
+            let mut n = [0u8; 1];
+            stream.read_exact(&mut n).map_err(upstream_failed)?;
+            n[0] as usize + 2
+        }
+        e => {
+            return Err(SocksError::Refused(format!(
+                "unexpected address type {}",
+                e
+            )))
+        }
+    };
+    let mut bound = vec![0u8; len];
+    stream.read_exact(&mut bound).map_err(upstream_failed)?;
+    stream.set_read_timeout(None).map_err(upstream_failed)?;
+    stream.set_write_timeout(None).map_err(upstream_failed)?;
+    Ok(stream)
+}
+
+/// check only the socks5 listener without connecting anywhere, return the latency
+pub fn probe_listener(proxy: &str, timeout: Duration) -> Result<Duration, SocksError> {
+    let now = Instant::now();
+    handshake(proxy, timeout)?;
+    Ok(now.elapsed())
+}
+
+/// check the proxy by connecting to `host:port` through it, return the latency
+pub fn probe(
+    proxy: &str,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<Duration, SocksError> {
+    let now = Instant::now();
+    connect(proxy, host, port, timeout)?;
+    Ok(now.elapsed())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::socks::{self, SocksError};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    /// a socks5 server answering every CONNECT with `rep`
+    fn serve(rep: u8) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 3];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+            let mut head = [0u8; 5];
+            stream.read_exact(&mut head).unwrap();
+            let mut rest = vec![0u8; head[4] as usize + 2];
+            stream.read_exact(&mut rest).unwrap();
+            stream
+                .write_all(&[5, rep, 0, 1, 127, 0, 0, 1, 0, 80])
+                .unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn test_probe() {
+        let timeout = Duration::from_secs(2);
+        let addr = serve(0);
+        assert!(socks::probe(addr.as_str(), "www.baidu.com", 443, timeout).is_ok());
+        let addr = serve(5);
+        assert!(matches!(
+            socks::probe(addr.as_str(), "www.baidu.com", 443, timeout),
+            Err(SocksError::UpstreamFailed(_))
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        assert!(matches!(
+            socks::probe(addr.as_str(), "www.baidu.com", 443, timeout),
+            Err(SocksError::Unreachable(_))
+        ));
+        // accepted by the backlog but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        assert!(matches!(
+            socks::probe_listener(addr.as_str(), Duration::from_millis(200)),
+            Err(SocksError::Timeout(_))
+        ));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::cfg::Config;
+use crate::utils;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// runtime state of a tunnel, saved as json in the runtime dir
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    /// ssh processes spawned by sshp, in start order
+    pub processes: Vec<Process>,
+    /// time of the next restart attempt when the probe is backing off
+    #[serde(default)]
+    pub next_restart: Option<String>,
+    /// the remote host connected when the tunnel has several
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// the probe, foreground supervisor or local server of the tunnel
+    #[serde(default)]
+    pub probe: Option<Process>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Process {
+    pub pid: usize,
+    pub cmdline: String,
+    pub start_time: String,
+}
+
+impl Process {
+    /// record a process spawned by sshp
+    pub fn new(pid: usize) -> Result<Self> {
+        match get_process_info(pid) {
+            Some((cmdline, start_time)) => Ok(Process {
+                pid,
+                cmdline,
+                start_time,
+            }),
+            None => anyhow::bail!("Process {} exited", pid),
+        }
+    }
+
+    /// alive and still the process sshp spawned, not another one reusing the pid
+    pub fn is_alive(&self) -> bool {
+        match get_process_info(self.pid) {
+            Some((cmdline, start_time)) => cmdline == self.cmdline && start_time == self.start_time,
+            None => false,
+        }
+    }
+}
+
+/// command line and start time of a process
+#[cfg(target_os = "linux")]
+pub fn get_process_info(pid: usize) -> Option<(String, String)> {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let cmdline = String::from_utf8_lossy(&cmdline)
+        .trim_end_matches('\0')
+        .replace('\0', " ");
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the fields after `(comm)`, the start time is the 22th field of stat
+    let fields = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .collect::<Vec<&str>>();
+    // zombies are dead
+    if fields.first() == Some(&"Z") {
+        return None;
+    }
+    Some((cmdline, fields.get(19)?.to_string()))
+}
+
+/// command line and start time of a process
+#[cfg(not(target_os = "linux"))]
+pub fn get_process_info(pid: usize) -> Option<(String, String)> {
+    let ps = |field: &str| -> Option<String> {
+        let output = std::process::Command::new("ps")
+            .args(vec!["-o", field, "-p", pid.to_string().as_str()])
+            .output()
+            .ok()?;
+        let res = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() || res.is_empty() {
+            return None;
+        }
+        Some(res)
+    };
+    Some((ps("command=")?, ps("lstart=")?))
+}
+
+impl State {
+    pub fn load(config: &Config, addr: &str) -> Self {
+        match std::fs::read_to_string(utils::get_state_file(config, addr)) {
+            Ok(e) => serde_json::from_str(e.as_str()).unwrap_or_default(),
+            Err(_) => State::default(),
+        }
+    }
+
+    /// a state without ssh processes, keeping the recorded probe
+    pub fn empty(config: &Config, addr: &str) -> Self {
+        State {
+            probe: State::load(config, addr).probe,
+            ..State::default()
+        }
+    }
+
+    /// record the probe of addr, None after it stopped
+    pub fn save_probe(config: &Config, addr: &str, probe: Option<Process>) -> Result<()> {
+        let mut state = State::load(config, addr);
+        state.probe = probe;
+        state.save(config, addr)
+    }
+
+    /// the recorded probe if it is still alive
+    pub fn get_alive_probe(&self) -> Option<&Process> {
+        self.probe.as_ref().filter(|e| e.is_alive())
+    }
+
+    pub fn save(&self, config: &Config, addr: &str) -> Result<()> {
+        std::fs::write(
+            utils::get_state_file(config, addr),
+            serde_json::to_string_pretty(self)?,
+        )?;
+        Ok(())
+    }
+
+    /// pids of the recorded processes still alive
+    pub fn get_alive_pids(&self) -> Vec<usize> {
+        self.processes
+            .iter()
+            .filter(|e| e.is_alive())
+            .map(|e| e.pid)
+            .collect()
+    }
+
+    /// all the recorded processes are alive
+    pub fn is_alive(&self) -> bool {
+        !self.processes.is_empty() && self.processes.iter().all(|e| e.is_alive())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::state::Process;
+
+    #[test]
+    fn test_process_is_alive() {
+        let mut process = Process::new(std::process::id() as usize).unwrap();
+        assert!(process.is_alive());
+        process.start_time = "0".to_string();
+        assert!(!process.is_alive());
+    }
+}
@@ -1,20 +1,43 @@
+use crate::cfg::{CheckMode, Config};
+use crate::error::SshpError;
+use crate::socks::{self, SocksError};
+use crate::state;
 use anyhow::Result;
 use fs2::FileExt;
-use regex::Regex;
 use std::fs::OpenOptions;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-pub fn stop_probe_process(addr: &str) -> Result<()> {
-    let probe_id = get_probe_id(addr)?;
-    if probe_id != 0 {
-        if let Err(e) = kill_child_by_pid(probe_id as usize) {
-            write_log(
-                &get_log_file(addr),
-                format!("{}, kill {} error, {}", addr, probe_id, e).as_str(),
-            );
+/// stop the probe recorded in the state of addr if it is still the process sshp started
+pub fn stop_probe_process(config: &Config, addr: &str) -> Result<()> {
+    let probe = match state::State::load(config, addr).probe {
+        Some(e) => e,
+        None => return Ok(()),
+    };
+    if probe.is_alive() {
+        let log_file = get_log_file(config, addr);
+        match kill_child_by_pid(probe.pid, config.get_stop_timeout()) {
+            Ok(Some(signal)) => write_log(
+                &log_file,
+                format!(
+                    "{}, probe {} stopped by {}",
+                    addr,
+                    probe.pid,
+                    get_signal_name(signal)
+                )
+                .as_str(),
+            ),
+            Ok(None) => {}
+            Err(e) => write_log(
+                &log_file,
+                format!("{}, kill {} error, {}", addr, probe.pid, e).as_str(),
+            ),
         }
     }
-    Ok(())
+    state::State::save_probe(config, addr, None)
 }
 
 pub fn print_with_color(text: &str, color: u8, hightlight: bool) {
@@ -31,119 +54,210 @@ pub fn print_with_color(text: &str, color: u8, hightlight: bool) {
     s.push("\x1b[0m");
     print!("{}", s.join(""));
 }
-pub fn check(addr: &str) -> Result<String> {
-    let child = std::process::Command::new("curl")
-        .arg("--no-progress-meter")
-        .arg("--socks5")
-        .arg(addr)
-        .arg("https://www.baidu.com")
-        .stderr(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::null())
-        .spawn()?
-        .wait_with_output()?;
-    if !child.stderr.is_empty() {
-        Ok(String::from_utf8_lossy(child.stderr.as_slice()).to_string())
-    } else {
-        Ok("".to_string())
-    }
-}
-
-pub fn check_result(res: Result<String>, addr: &str, echo: bool) -> bool {
-    let log_file = get_log_file(addr);
-    match res {
-        Ok(e) => {
-            if e.is_empty()
-                || (!e.contains("Connection refused")
-                    && !e.contains("connection to proxy closed")
-                    && !e.contains("curl: ("))
-            {
-                if echo {
-                    print_with_color("Open Dynamic Proxy Success, listen addr is ", 32, false);
-                    print_with_color(addr, 37, true);
-                    print_with_color(".", 32, true);
-                    println!();
-                } else {
-                    write_log(
-                        &log_file,
-                        format!("Open Dynamic Proxy Success, listen addr is {}.", addr).as_str(),
-                    )
+/// check the tunnel by connecting the check targets of the selected tunnel through
+/// the socks5 listener, return the slowest latency of the succeeded targets,
+/// forward tunnels are checked by their forwards
+pub fn check(config: &Config, addr: &str) -> Result<Duration> {
+    let timeout = Duration::from_secs(10);
+    match config.get_tunnel_type() {
+        "local" => return check_local_forwards(config, timeout),
+        "remote" => return check_remote_forwards(config, timeout),
+        _ => {}
+    }
+    let targets = config.get_check_targets()?;
+    let mode = config.get_check_mode()?;
+    if targets.is_empty() {
+        return Ok(socks::probe_listener(addr, timeout)?);
+    }
+    let mut latency = Duration::from_secs(0);
+    let mut error = None;
+    for target in targets {
+        match socks::probe(addr, target.host.as_str(), target.port, timeout) {
+            Ok(e) => {
+                latency = latency.max(e);
+                if mode == CheckMode::Any {
+                    return Ok(latency);
                 }
-                true
-            } else {
-                if echo {
-                    print_with_color("Listen ", 32, false);
-                    print_with_color(addr, 37, true);
-                    print_with_color("success, ", 32, false);
-                    print_with_color(
-                        "but curl www.baidu.com through the tunnel failed:\n",
-                        31,
-                        true,
-                    );
-                    print_with_color(e.as_str(), 31, true);
-                    println!();
-                } else {
-                    write_log(
-                        &log_file,
-                        format!(
-                        "Listen {} success, but curl www.baidu.com through the tunnel failed:\n{}.",
-                        addr, e.trim())
-                        .as_str(),
-                    )
+            }
+            Err(e) => {
+                // the listener itself is down or hung, no need to try other targets
+                if mode == CheckMode::All
+                    || matches!(e, SocksError::Unreachable(_) | SocksError::Timeout(_))
+                {
+                    return Err(e.into());
                 }
-                false
+                error = Some(e);
             }
         }
-        Err(e) => {
+    }
+    match error {
+        Some(e) => Err(e.into()),
+        None => Ok(latency),
+    }
+}
+
+/// connect the listen side of every local forward
+fn check_local_forwards(config: &Config, timeout: Duration) -> Result<Duration> {
+    let now = std::time::Instant::now();
+    if config.get_check_targets()?.is_empty() {
+        return Ok(now.elapsed());
+    }
+    for forward in config.get_forwards()? {
+        let host = forward.get_connect_host();
+        let addrs = (host, forward.port).to_socket_addrs()?.collect::<Vec<_>>();
+        if !addrs
+            .iter()
+            .any(|e| std::net::TcpStream::connect_timeout(e, timeout).is_ok())
+        {
+            anyhow::bail!("local forward {} unreachable", forward.spec());
+        }
+    }
+    Ok(now.elapsed())
+}
+
+/// connect the listen side of every remote forward on the remote by `ssh -W`
+fn check_remote_forwards(config: &Config, timeout: Duration) -> Result<Duration> {
+    let now = std::time::Instant::now();
+    if config.get_check_targets()?.is_empty() {
+        return Ok(now.elapsed());
+    }
+    for forward in config.get_forwards()? {
+        let mut args = config.get_ssh_extra_args();
+        args.extend(config.get_host_key_args(None)?);
+        args.extend(vec![
+            "-o".to_string(),
+            "BatchMode=yes".to_string(),
+            "-o".to_string(),
+            format!("ConnectTimeout={}", timeout.as_secs()),
+            "-W".to_string(),
+            format!("{}:{}", forward.get_connect_host(), forward.port),
+        ]);
+        args.extend(config.get_forward_target_args());
+        let mut child = std::process::Command::new("ssh")
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::piped())
+            .spawn()?;
+        let started = std::time::Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if started.elapsed() >= timeout * 2 {
+                child.kill().ok();
+                child.wait().ok();
+                anyhow::bail!("remote forward {} check timeout", forward.spec());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        };
+        if !status.success() {
+            let mut stderr = String::new();
+            if let Some(mut e) = child.stderr.take() {
+                e.read_to_string(&mut stderr).ok();
+            }
+            anyhow::bail!(
+                "remote forward {} unreachable on the remote, {}",
+                forward.spec(),
+                stderr.trim()
+            );
+        }
+    }
+    Ok(now.elapsed())
+}
+
+/// what the selected tunnel opens in messages
+pub fn get_tunnel_kind(config: &Config) -> &'static str {
+    match config.get_tunnel_type() {
+        "local" | "remote" => "Forwards",
+        _ => "Dynamic Proxy",
+    }
+}
+
+pub fn check_result(config: &Config, res: Result<Duration>, addr: &str, echo: bool) -> bool {
+    let log_file = get_log_file(config, addr);
+    match res {
+        Ok(latency) => {
             if echo {
-                print_with_color("Listen ", 32, false);
+                print_with_color(
+                    format!("Open {} Success, listen addr is ", get_tunnel_kind(config)).as_str(),
+                    32,
+                    false,
+                );
                 print_with_color(addr, 37, true);
-                print_with_color("success, ", 32, false);
                 print_with_color(
-                    "but little error happen when check the tunnel by curling www.baidu.com: \n",
-                    31,
+                    format!(", latency {}ms.", latency.as_millis()).as_str(),
+                    32,
                     true,
                 );
-                print_with_color(e.to_string().as_str(), 31, true);
                 println!();
             } else {
                 write_log(
                     &log_file,
                     format!(
-                        "Listen {} success, but little error happen when check the tunnel by curling www.baidu.com:\n{}.", addr, e.to_string().trim(),
-                        ).as_str())
+                        "Open {} Success, listen addr is {}, latency {}ms.",
+                        get_tunnel_kind(config),
+                        addr,
+                        latency.as_millis()
+                    )
+                    .as_str(),
+                )
             }
             true
         }
+        Err(e) => {
+            if echo {
+                print_with_color("Listen ", 32, false);
+                print_with_color(addr, 37, true);
+                print_with_color(" success, ", 32, false);
+                print_with_color("but check through the tunnel failed:\n", 31, true);
+                print_with_color(e.to_string().as_str(), 31, true);
+                println!();
+            } else {
+                write_log(
+                    &log_file,
+                    format!(
+                        "Listen {} success, but check through the tunnel failed:\n{}.",
+                        addr, e
+                    )
+                    .as_str(),
+                )
+            }
+            false
+        }
     }
 }
 
-/// get child pids by listen addr
-pub fn get_pids(addr: &str) -> Result<Vec<usize>> {
-    let mut res = Vec::new();
-    let mut ps = std::process::Command::new("ps")
-        .arg("aux")
-        .stdout(std::process::Stdio::piped())
-        .spawn()?;
-    if let Some(ps_output) = ps.stdout.take() {
-        let grep = std::process::Command::new("grep")
-            .arg(addr)
-            .stdin(ps_output)
-            .stdout(std::process::Stdio::piped())
-            .spawn()?;
-        ps.wait()?;
-        let grep_output = String::from_utf8_lossy(&grep.wait_with_output()?.stdout).to_string();
-        let re = Regex::new(r"\s+").unwrap();
-        for x in grep_output.split('\n') {
-            let x = x.trim();
-            if x.is_empty() || x.contains("grep") {
-                continue;
+/// run the health check of a running tunnel, return false if it failed
+/// `probe_health_check_failures` times in a row and should be restarted
+pub fn health_check(config: &Config, addr: &str, failed_times: &mut i32) -> bool {
+    match check(config, addr) {
+        Ok(_) => {
+            *failed_times = 0;
+            true
+        }
+        Err(e) => {
+            *failed_times += 1;
+            let log_file = get_log_file(config, addr);
+            write_log(
+                &log_file,
+                format!("{} health check {}th failed, {}", addr, failed_times, e).as_str(),
+            );
+            if *failed_times < config.get_probe_health_check_failures() {
+                return true;
             }
-            let replace = re.replace_all(x, "!#");
-            let pid: &str = replace.split("!#").collect::<Vec<&str>>()[1];
-            res.push(pid.parse()?)
+            write_log(
+                &log_file,
+                format!(
+                    "{} health check failed {} times, restart the tunnel",
+                    addr, failed_times
+                )
+                .as_str(),
+            );
+            *failed_times = 0;
+            false
         }
     }
-    Ok(res)
 }
 
 pub fn get_child_pid(ppid: usize) -> Result<usize> {
@@ -163,14 +277,42 @@ pub fn get_child_pid(ppid: usize) -> Result<usize> {
     Ok(0)
 }
 
-/// kill child by pid unix
+/// kill child by pid unix, SIGTERM first and SIGKILL if it is still alive after timeout,
+/// return the signal which ended it, None if it is not running
 #[cfg(target_family = "unix")]
-pub fn kill_child_by_pid(pid: usize) -> Result<()> {
-    std::process::Command::new("kill")
-        .args(vec!["-9", pid.to_string().as_str()])
-        .stderr(std::process::Stdio::null())
-        .status()?;
-    Ok(())
+pub fn kill_child_by_pid(pid: usize, timeout: Duration) -> Result<Option<i32>> {
+    if !send_signal(pid, libc::SIGTERM) {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ESRCH) {
+            return Ok(None);
+        }
+        anyhow::bail!("send SIGTERM to {} error, {}", pid, e);
+    }
+    let now = std::time::Instant::now();
+    while now.elapsed() < timeout {
+        if !is_process_alive(pid) {
+            return Ok(Some(libc::SIGTERM));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    if !send_signal(pid, libc::SIGKILL) {
+        let e = std::io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ESRCH) {
+            return Ok(Some(libc::SIGTERM));
+        }
+        anyhow::bail!("send SIGKILL to {} error, {}", pid, e);
+    }
+    Ok(Some(libc::SIGKILL))
+}
+
+#[cfg(target_family = "unix")]
+pub fn get_signal_name(signal: i32) -> &'static str {
+    match signal {
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGINT => "SIGINT",
+        _ => "signal",
+    }
 }
 
 /// kill child by pid windows
@@ -182,10 +324,179 @@ pub fn kill_child_by_pid_windows(pid: usize) -> Result<()> {
     Ok(())
 }
 
-pub fn get_avaliable_port() -> u16 {
-    (1025..65535)
-        .find(|port| std::net::TcpListener::bind(("127.0.0.1", *port)).is_ok())
-        .unwrap_or(50002)
+/// check whether the process is alive by signal 0, reap it if it is a child exited,
+/// zombies not reaped by their parent are dead
+#[cfg(target_family = "unix")]
+pub fn is_process_alive(pid: usize) -> bool {
+    unsafe {
+        if libc::waitpid(pid as libc::pid_t, std::ptr::null_mut(), libc::WNOHANG) > 0 {
+            return false;
+        }
+    }
+    send_signal(pid, 0) && state::get_process_info(pid).is_some()
+}
+
+/// n free ports of host, `127.0.0.1` or `::1`
+pub fn get_avaliable_ports(host: &str, n: usize) -> Vec<u16> {
+    // hold the listeners until enough ports are found
+    let mut listeners = Vec::new();
+    for port in 1025..65535 {
+        if listeners.len() >= n {
+            break;
+        }
+        if let Ok(e) = std::net::TcpListener::bind((host, port)) {
+            listeners.push(e);
+        }
+    }
+    listeners
+        .iter()
+        .filter_map(|e| e.local_addr().ok())
+        .map(|e| e.port())
+        .collect()
+}
+
+/// whether host:port accepts tcp connections in 5s
+pub fn is_reachable(host: &str, port: usize) -> bool {
+    match (host, port as u16).to_socket_addrs() {
+        Ok(addrs) => addrs
+            .into_iter()
+            .any(|e| std::net::TcpStream::connect_timeout(&e, Duration::from_secs(5)).is_ok()),
+        Err(_) => false,
+    }
+}
+
+/// fail if something already accepts connections on any of addrs before the ssh listens
+/// on them
+pub fn ensure_not_listening(addrs: &[String]) -> Result<()> {
+    match addrs
+        .iter()
+        .find(|e| std::net::TcpStream::connect(e.as_str()).is_ok())
+    {
+        Some(e) => Err(SshpError::AddressInUse(format!("{} is already in use", e)).into()),
+        None => Ok(()),
+    }
+}
+
+/// wait until the ssh listens on all of addrs, or stays alive for 1s if it listens on
+/// nothing locally, fail once it exits, reports an error in `stderr` or `timeout` passes
+pub fn wait_for_listen(
+    addrs: &[String],
+    child: &mut std::process::Child,
+    timeout: Duration,
+    stderr: impl Fn() -> String,
+) -> Result<()> {
+    let now = std::time::Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            anyhow::bail!("ssh exited, {}", status);
+        }
+        if let Some(e) = stderr()
+            .lines()
+            .find(|e| e.contains("failed") || e.contains("Address already in use"))
+        {
+            anyhow::bail!("{}", e.trim());
+        }
+        let pending: Vec<&str> = addrs
+            .iter()
+            .map(|e| e.as_str())
+            .filter(|e| std::net::TcpStream::connect(e).is_err())
+            .collect();
+        if addrs.is_empty() && now.elapsed() >= Duration::from_secs(1)
+            || !addrs.is_empty() && pending.is_empty()
+        {
+            return Ok(());
+        }
+        if now.elapsed() >= timeout {
+            match pending.is_empty() {
+                false => anyhow::bail!(
+                    "ssh not listening on {} after {}s",
+                    pending.join(", "),
+                    timeout.as_secs()
+                ),
+                true => anyhow::bail!("ssh not ready after {}s", timeout.as_secs()),
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// copy the stderr of child to ours and the log line by line, the returned buffer keeps
+/// the first 64KB for checking the startup
+pub fn stream_stderr(
+    child: &mut std::process::Child,
+    log_path: &std::path::Path,
+) -> Arc<Mutex<String>> {
+    let buf = Arc::new(Mutex::new(String::new()));
+    if let Some(stderr) = child.stderr.take() {
+        let buf = buf.clone();
+        let log_path = log_path.to_path_buf();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(|e| e.ok()) {
+                eprintln!("{}", line);
+                write_log(&log_path, line.as_str());
+                if let Ok(mut e) = buf.lock() {
+                    if e.len() < 64 * 1024 {
+                        e.push_str(line.as_str());
+                        e.push('\n');
+                    }
+                }
+            }
+        });
+    }
+    buf
+}
+
+/// the stderr of an exited child buffered by `stream_stderr`, waiting up to 1s for the
+/// rest in the pipe, the copying thread drops its clone of the buffer once it is done
+pub fn read_streamed_stderr(buf: &Arc<Mutex<String>>) -> String {
+    let now = std::time::Instant::now();
+    while Arc::strong_count(buf) > 1 && now.elapsed() < Duration::from_secs(1) {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    buf.lock().map(|e| e.clone()).unwrap_or_default()
+}
+
+/// copy both directions until the upstream closes
+pub fn relay(client: std::net::TcpStream, upstream: std::net::TcpStream) {
+    let (mut client_read, mut upstream_write) = match (client.try_clone(), upstream.try_clone()) {
+        (Ok(a), Ok(b)) => (a, b),
+        _ => return,
+    };
+    let t = std::thread::spawn(move || {
+        std::io::copy(&mut client_read, &mut upstream_write).ok();
+        upstream_write.shutdown(std::net::Shutdown::Write).ok();
+    });
+    let (mut upstream_read, mut client_write) = (upstream, client);
+    std::io::copy(&mut upstream_read, &mut client_write).ok();
+    client_write.shutdown(std::net::Shutdown::Both).ok();
+    t.join().ok();
+}
+
+static TERMINATE_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn on_terminate_signal(signal: libc::c_int) {
+    TERMINATE_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// record SIGTERM and SIGINT instead of exiting
+pub fn handle_terminate_signals() {
+    let handler = on_terminate_signal as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+/// the SIGTERM or SIGINT received since `handle_terminate_signals`
+pub fn get_terminate_signal() -> Option<i32> {
+    match TERMINATE_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        e => Some(e),
+    }
+}
+
+pub fn send_signal(pid: usize, signal: i32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, signal) == 0 }
 }
 
 pub fn write_log(addr: &std::path::PathBuf, msg: &str) {
@@ -204,22 +515,72 @@ pub fn write_log(addr: &std::path::PathBuf, msg: &str) {
     }
 }
 
-pub fn get_pid_file(addr: &str) -> std::path::PathBuf {
-    let pid_file_name = addr.replace(':', "-") + ".pid";
-    std::path::PathBuf::from("/var/run/sshp").join(pid_file_name)
+/// the runtime dir of the state and log files, created with 0700 if not exists
+pub fn get_runtime_dir(config: &Config) -> std::path::PathBuf {
+    let dir = config.get_runtime_dir();
+    if !dir.exists() {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(target_family = "unix")]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        if let Err(e) = builder.create(&dir) {
+            print_with_color(
+                format!(
+                    "Create runtime dir {} error, {}\n",
+                    dir.to_string_lossy(),
+                    e
+                )
+                .as_str(),
+                31,
+                false,
+            );
+        }
+    }
+    dir
 }
 
-pub fn get_probe_id(addr: &str) -> Result<i32> {
-    let path = get_pid_file(addr);
-    if !path.exists() {
-        return Ok(0);
+/// `host-port` of addr as the name of its runtime files, the colons of ipv6 become `_`
+fn get_file_stem(addr: &str) -> String {
+    match addr.rsplit_once(':') {
+        Some((host, port)) => format!(
+            "{}-{}",
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .replace(':', "_"),
+            port
+        ),
+        None => addr.to_string(),
     }
-    Ok(std::fs::read_to_string(path)?.parse::<i32>()?)
 }
 
-pub fn get_log_file(addr: &str) -> std::path::PathBuf {
-    let log_file_name = addr.replace(':', "-") + ".log";
-    std::path::PathBuf::from("/var/run/sshp").join(log_file_name)
+pub fn get_state_file(config: &Config, addr: &str) -> std::path::PathBuf {
+    let state_file_name = get_file_stem(addr) + ".json";
+    get_runtime_dir(config).join(state_file_name)
+}
+
+pub fn get_log_file(config: &Config, addr: &str) -> std::path::PathBuf {
+    let log_file_name = get_file_stem(addr) + ".log";
+    get_runtime_dir(config).join(log_file_name)
+}
+
+/// time of the last start or restart recorded in the log of addr
+pub fn get_last_start_time(config: &Config, addr: &str) -> Option<String> {
+    let log = std::fs::read_to_string(get_log_file(config, addr)).ok()?;
+    let start = format!("{} start ...", addr);
+    let restart = format!("{} start in probe ...", addr);
+    let foreground = format!("{} start in foreground ...", addr);
+    log.lines()
+        .rev()
+        .find(|e| {
+            e.ends_with(start.as_str())
+                || e.ends_with(restart.as_str())
+                || e.ends_with(foreground.as_str())
+        })
+        .and_then(|e| e.get(..19))
+        .map(|e| e.to_string())
 }
 
 #[cfg(test)]
@@ -227,7 +588,46 @@ mod test {
     use crate::utils;
 
     #[test]
-    fn test_get_pids() {
-        utils::get_pids("localhost:50003").unwrap();
+    fn test_get_avaliable_ports() {
+        let mut ports = utils::get_avaliable_ports("127.0.0.1", 3);
+        ports.dedup();
+        assert_eq!(ports.len(), 3);
+    }
+
+    #[test]
+    fn test_wait_for_listen() {
+        let timeout = std::time::Duration::from_secs(2);
+        let mut child = std::process::Command::new("sleep")
+            .arg("5")
+            .spawn()
+            .unwrap();
+        assert!(utils::wait_for_listen(&[], &mut child, timeout, String::new).is_ok());
+        let res = utils::wait_for_listen(&[], &mut child, timeout, || {
+            "bind [127.0.0.1]:1080: Address already in use\n".to_string()
+        });
+        assert!(res
+            .unwrap_err()
+            .to_string()
+            .contains("Address already in use"));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = vec![listener.local_addr().unwrap().to_string()];
+        assert!(utils::wait_for_listen(&addrs, &mut child, timeout, String::new).is_ok());
+        assert!(utils::ensure_not_listening(&addrs).is_err());
+        let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let other = unused.local_addr().unwrap().to_string();
+        drop(unused);
+        let addrs = vec![addrs[0].clone(), other];
+        let res = utils::wait_for_listen(&addrs, &mut child, timeout, String::new);
+        assert!(res.unwrap_err().to_string().contains(addrs[1].as_str()));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(utils::wait_for_listen(&addrs[..1], &mut child, timeout, String::new).is_err());
+    }
+
+    #[test]
+    fn test_get_file_stem() {
+        assert_eq!(utils::get_file_stem("localhost:50001"), "localhost-50001");
+        assert_eq!(utils::get_file_stem("[::1]:50001"), "__1-50001");
+        assert_eq!(utils::get_file_stem("db"), "db");
     }
 }